@@ -1,35 +1,127 @@
-use std::sync::Arc;
-
-use crate::db;
+use crate::archive::{ArchiveFormat, handle_archive};
+use crate::compression::{Encoding, check_on_read, open_decoded};
+use crate::conf;
+use crate::db::{Db, Repository, UrlFile};
 use crate::error::AppError;
-use crate::utils::get_full_path;
+use crate::storage::storage;
 
 use axum::{
     body::Body,
     extract::{Path, State},
     http,
-    response::IntoResponse,
+    response::{IntoResponse, Json},
 };
-use deadpool_sqlite::Pool;
+use chrono::Utc;
 
-async fn get_file(db_pool: &Pool, tail: &str) -> Result<(Vec<u8>, String), AppError> {
-    let (filename, mimetype) = db::get_file_by_url(&db_pool, &tail)
+async fn blob_size(filename: &str) -> Result<u64, AppError> {
+    return storage()
+        .size(filename)
+        .await?
+        .ok_or(AppError::IO(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("blob {} is missing", filename),
+        )));
+}
+
+async fn lookup_file(db: &dyn Repository, tail: &str) -> Result<(UrlFile, Encoding), AppError> {
+    let file = db
+        .get_file_by_url(tail)
         .await?
         .ok_or(AppError::TailNotFound)?;
-    let data = tokio::fs::read(get_full_path(&filename)).await?;
-    Ok((data, mimetype))
+    let encoding = Encoding::of_file(&file)?;
+    return Ok((file, encoding));
 }
 
-pub async fn handle_access(
-    State(db_pool): State<Arc<Pool>>,
-    Path(tail): Path<String>,
-) -> http::Response<Body> {
-    match get_file(&db_pool, &tail).await {
-        Ok((data, mimetype)) => return ([("Content-Type", mimetype)], data).into_response(),
+/// Size of the original content, rows of older versions don't record it but
+/// their blobs are never compressed.
+async fn content_size(file: &UrlFile) -> Result<u64, AppError> {
+    return match file.size {
+        Some(size) => Ok(size),
+        None => blob_size(&file.file_sha256sum).await,
+    };
+}
+
+struct FileResponse {
+    body: Body,
+    size: Option<u64>,
+    mimetype: String,
+    /// encoding the body is sent in
+    content_encoding: Encoding,
+    /// whether the response depends on `Accept-Encoding`
+    vary: bool,
+}
+
+/// Compressed blobs are passed through when the client accepts their
+/// encoding and decompressed on the fly otherwise.
+async fn get_file(
+    db: &dyn Repository,
+    tail: &str,
+    headers: &http::HeaderMap,
+    head: bool,
+) -> Result<FileResponse, AppError> {
+    let (file, encoding) = lookup_file(db, tail).await?;
+    if !head && let Err(e) = db.record_access(tail, Utc::now().timestamp()).await {
+        tracing::warn!("cannot record access of {}: {}", tail, e);
+    }
+    let content_encoding = match encoding.accepted_by(headers) {
+        true => encoding,
+        false => Encoding::Identity,
+    };
+
+    let (body, size) = match (head, content_encoding == encoding) {
+        (true, true) => (Body::empty(), Some(blob_size(&file.file_sha256sum).await?)),
+        (true, false) => (Body::empty(), Some(content_size(&file).await?)),
+        (false, true) => {
+            check_on_read(&file).await?;
+            let (stream, size) = storage().stream(&file.file_sha256sum).await?;
+            (Body::from_stream(stream), Some(size))
+        }
+        (false, false) => {
+            let (stream, size) = open_decoded(&file).await?;
+            (Body::from_stream(stream), size)
+        }
+    };
+
+    return Ok(FileResponse {
+        body,
+        size,
+        mimetype: file.mimetype,
+        content_encoding,
+        vary: encoding != Encoding::Identity,
+    });
+}
+
+fn file_response(result: Result<FileResponse, AppError>) -> http::Response<Body> {
+    match result {
+        Ok(file) => {
+            let mut response = ([("Content-Type", file.mimetype)], file.body).into_response();
+            let headers = response.headers_mut();
+            if let Some(size) = file.size {
+                headers.insert(http::header::CONTENT_LENGTH, size.into());
+            }
+            if file.content_encoding != Encoding::Identity {
+                headers.insert(
+                    http::header::CONTENT_ENCODING,
+                    http::HeaderValue::from_static(file.content_encoding.as_str()),
+                );
+            }
+            if file.vary {
+                headers.insert(
+                    http::header::VARY,
+                    http::HeaderValue::from_static("Accept-Encoding"),
+                );
+            }
+            return response;
+        }
         Err(e) => match e {
             AppError::TailNotFound => {
                 return http::StatusCode::NOT_FOUND.into_response();
             }
+            // the url outlived its blob, `fsck --repair` removes such urls
+            AppError::IO(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::warn!("blob of a url is missing from storage: {}", e);
+                return http::StatusCode::NOT_FOUND.into_response();
+            }
             _ => {
                 tracing::error!("{}", e);
                 return http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
@@ -37,3 +129,54 @@ pub async fn handle_access(
         },
     }
 }
+
+pub async fn handle_access(
+    State(db): State<Db>,
+    Path(tail): Path<String>,
+    headers: http::HeaderMap,
+) -> http::Response<Body> {
+    if let Some((collection, format)) = ArchiveFormat::split_path(&tail) {
+        return handle_archive(db.as_ref(), collection, format, false).await;
+    }
+    return file_response(get_file(db.as_ref(), &tail, &headers, false).await);
+}
+
+/// Same headers as `handle_access`, but only stats the file instead of reading it.
+pub async fn handle_access_head(
+    State(db): State<Db>,
+    Path(tail): Path<String>,
+    headers: http::HeaderMap,
+) -> http::Response<Body> {
+    if let Some((collection, format)) = ArchiveFormat::split_path(&tail) {
+        return handle_archive(db.as_ref(), collection, format, true).await;
+    }
+    return file_response(get_file(db.as_ref(), &tail, &headers, true).await);
+}
+
+async fn get_info(db: &dyn Repository, tail: &str) -> Result<serde_json::Value, AppError> {
+    let (info, _) = lookup_file(db, tail).await?;
+    let size = content_size(&info).await?;
+    return Ok(serde_json::json!({
+        "url": format!("{}/{}", conf().base_url, tail),
+        "tail": tail,
+        "sha256": info.file_sha256sum,
+        "size": size,
+        "mimetype": info.mimetype,
+        "created_at": info.created_at,
+        "expires_at": info.expires_at,
+        "last_accessed_at": info.last_accessed_at,
+        "access_count": info.access_count,
+    }));
+}
+
+/// Describes what a tail points at without downloading it.
+pub async fn handle_info(State(db): State<Db>, Path(tail): Path<String>) -> http::Response<Body> {
+    return match get_info(db.as_ref(), &tail).await {
+        Ok(info) => Json(info).into_response(),
+        Err(AppError::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::warn!("blob of a url is missing from storage: {}", e);
+            AppError::TailNotFound.into_response(true)
+        }
+        Err(e) => e.into_response(true),
+    };
+}
@@ -0,0 +1,165 @@
+use crate::compression::open_decoded;
+use crate::db::{Repository, UrlFile};
+use crate::error::AppError;
+
+use async_compression::tokio::write::GzipEncoder;
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use axum::{
+    body::{Body, Bytes},
+    http,
+    response::IntoResponse,
+};
+use futures_util::StreamExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinHandle;
+use tokio_util::compat::FuturesAsyncWriteCompatExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// Splits an access path like `abcd.zip` into the collection tail and the format.
+    pub fn split_path(path: &str) -> Option<(&str, ArchiveFormat)> {
+        if let Some(tail) = path.strip_suffix(".zip") {
+            return Some((tail, ArchiveFormat::Zip));
+        }
+        if let Some(tail) = path.strip_suffix(".tar.gz") {
+            return Some((tail, ArchiveFormat::TarGz));
+        }
+        return None;
+    }
+
+    fn extension(self) -> &'static str {
+        return match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        };
+    }
+
+    fn mimetype(self) -> &'static str {
+        return match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarGz => "application/gzip",
+        };
+    }
+}
+
+async fn write_zip<W: AsyncWrite + Unpin>(
+    writer: W,
+    files: Vec<(String, UrlFile)>,
+) -> Result<(), AppError> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for (filename, file) in files {
+        let (stream, _) = open_decoded(&file).await?;
+        let mut file = StreamReader::new(stream);
+        let entry = ZipEntryBuilder::new(filename.into(), Compression::Deflate);
+        let mut entry_writer = zip.write_entry_stream(entry).await?.compat_write();
+        tokio::io::copy(&mut file, &mut entry_writer).await?;
+        entry_writer.into_inner().close().await?;
+    }
+    zip.close().await?.into_inner().shutdown().await?;
+    return Ok(());
+}
+
+async fn write_tar_gz<W: AsyncWrite + Unpin + Send + Sync + 'static>(
+    writer: W,
+    files: Vec<(String, UrlFile)>,
+) -> Result<(), AppError> {
+    let mut tar = tokio_tar::Builder::new(GzipEncoder::new(writer));
+    for (filename, file) in files {
+        let (stream, size) = open_decoded(&file).await?;
+        let size = size.ok_or(AppError::Storage(format!(
+            "size of blob {} is unknown",
+            file.file_sha256sum
+        )))?;
+        let file = StreamReader::new(stream);
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, filename, file).await?;
+    }
+    tar.into_inner().await?.shutdown().await?;
+    return Ok(());
+}
+
+async fn write_archive(
+    writer: DuplexStream,
+    format: ArchiveFormat,
+    files: Vec<(String, UrlFile)>,
+) -> Result<(), AppError> {
+    return match format {
+        ArchiveFormat::Zip => write_zip(writer, files).await,
+        ArchiveFormat::TarGz => write_tar_gz(writer, files).await,
+    };
+}
+
+/// Streams what `write_archive` writes into the pipe, then fails the body if
+/// it failed, so the transfer is aborted instead of ending in a clean EOF on
+/// a truncated archive.
+fn archive_body(reader: DuplexStream, writer: JoinHandle<Result<(), AppError>>) -> Body {
+    let outcome = futures_util::stream::once(writer).filter_map(|result| async move {
+        let error = match result {
+            Ok(Ok(())) => return None,
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => e.to_string(),
+        };
+        tracing::error!("cannot write archive: {}", error);
+        return Some(Err::<Bytes, _>(std::io::Error::other(error)));
+    });
+    return Body::from_stream(ReaderStream::new(reader).chain(outcome));
+}
+
+/// Streams an archive of every file in a collection, the archive is produced
+/// by a background task through a bounded pipe so it's never held in memory.
+/// With `head` set only the headers are returned.
+pub async fn handle_archive(
+    db: &dyn Repository,
+    collection: &str,
+    format: ArchiveFormat,
+    head: bool,
+) -> http::Response<Body> {
+    let files = match db.get_collection_files(collection).await {
+        Ok(files) if files.is_empty() => return http::StatusCode::NOT_FOUND.into_response(),
+        Ok(files) => files,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let body = match head {
+        // unsized like the real archive stream, so no bogus Content-Length is sent
+        true => Body::from_stream(futures_util::stream::empty::<std::io::Result<Vec<u8>>>()),
+        false => {
+            let (writer, reader) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+            archive_body(
+                reader,
+                tokio::task::spawn(write_archive(writer, format, files)),
+            )
+        }
+    };
+
+    return (
+        [
+            ("Content-Type", format.mimetype().to_string()),
+            (
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    collection,
+                    format.extension()
+                ),
+            ),
+        ],
+        body,
+    )
+        .into_response();
+}
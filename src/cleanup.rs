@@ -1,44 +1,279 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
-use crate::conf;
-use crate::db::{cleanup_expired_urls, cleanup_unreachable_files};
+use crate::compression::{Encoding, verify_blob};
+use crate::config::{CONFIG_RELOADED, Config, conf};
+use crate::db::{Db, Repository};
 use crate::error::AppError;
+use crate::storage::{BLOB_LOCK, storage};
 
 use chrono::Utc;
-use deadpool_sqlite::Pool;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
-async fn cleanup_urls(db_pool: &Pool) -> Result<(), AppError> {
+/// Names loaded from `files` per query while collecting the live blobs.
+const LIVE_BATCH_SIZE: usize = 1000;
+/// Blobs deleted per acquisition of `BLOB_LOCK`, uploads wait while it's held.
+const DELETE_BATCH_SIZE: usize = 100;
+
+async fn cleanup_urls(db: &dyn Repository) -> Result<(), AppError> {
     let now = Utc::now().timestamp();
-    cleanup_expired_urls(&db_pool, now).await?;
+    db.cleanup_expired_urls(now).await?;
     return Ok(());
 }
 
-async fn cleanup_files(db_pool: &Pool) -> Result<(), AppError> {
-    cleanup_unreachable_files(&db_pool).await?;
+/// What a file cleanup pass did.
+#[derive(Default, Debug)]
+pub struct CleanupStats {
+    /// blobs referred to by `files`
+    pub live: usize,
+    /// blobs found in storage
+    pub scanned: usize,
+    /// unreferenced blobs kept for being written within the grace period
+    pub recent: usize,
+    pub deleted: usize,
+    pub failed: usize,
+    /// temporary files of crashed or aborted writes deleted
+    pub stale_temp: usize,
+    pub elapsed: Duration,
+}
+
+/// Loads every name in `files`, one page per query so no connection is held
+/// for long.
+async fn load_live_files(db: &dyn Repository) -> Result<HashSet<String>, AppError> {
+    let mut live = HashSet::new();
+    let mut after = String::new();
+    loop {
+        let names = db.list_file_names(&after, LIVE_BATCH_SIZE).await?;
+        let Some(last) = names.last() else {
+            break;
+        };
+        after = last.clone();
+        live.extend(names);
+    }
+    return Ok(live);
+}
+
+/// Deletes blobs no `files` row refers to. The live blobs are loaded in pages
+/// and storage is walked without holding a connection. Blobs written within
+/// `cleanup_grace_period` are skipped, they may belong to an upload whose urls
+/// aren't committed yet, and the rest is deleted in batches, each checked
+/// again under `BLOB_LOCK` in case a concurrent upload referenced or wrote
+/// it again.
+/// Stops between batches once `shutdown` is cancelled.
+async fn cleanup_files(
+    db: &dyn Repository,
+    shutdown: &CancellationToken,
+) -> Result<CleanupStats, AppError> {
+    let started = Instant::now();
+    let mut stats = CleanupStats::default();
+    let live = load_live_files(db).await?;
+    stats.live = live.len();
+
+    let cutoff = Utc::now().timestamp() - conf().cleanup_grace_period as i64;
+    let mut candidates = Vec::new();
+    for blob in storage().list().await? {
+        stats.scanned += 1;
+        if live.contains(&blob.name) {
+            continue;
+        }
+        match blob.modified_at <= cutoff {
+            true => candidates.push(blob.name),
+            false => stats.recent += 1,
+        }
+    }
+    drop(live);
+    // a write in progress doesn't leave its temporary file untouched that long
+    stats.stale_temp = storage().remove_stale_temp(cutoff).await?;
+
+    for batch in candidates.chunks(DELETE_BATCH_SIZE) {
+        if shutdown.is_cancelled() {
+            break;
+        }
+        let _guard = BLOB_LOCK.write().await;
+        for name in db.filter_unreachable_files(batch.to_vec()).await? {
+            // the lock doesn't reach other processes, one of them may have
+            // written the blob again since storage was listed
+            match storage().modified_at(&name).await {
+                Ok(Some(modified_at)) if modified_at > cutoff => {
+                    stats.recent += 1;
+                    continue;
+                }
+                Ok(Some(_)) => (),
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::warn!("cannot check blob {}: {}", name, e);
+                    stats.failed += 1;
+                    continue;
+                }
+            }
+            match storage().delete(&name).await {
+                Ok(_) => stats.deleted += 1,
+                Err(e) => {
+                    tracing::warn!("cannot delete blob {}: {}", name, e);
+                    stats.failed += 1;
+                }
+            }
+        }
+        tracing::debug!(
+            "deleted {} of {} unreachable blobs",
+            stats.deleted,
+            candidates.len()
+        );
+    }
+
+    stats.elapsed = started.elapsed();
+    if stats.deleted + stats.failed + stats.stale_temp > 0 {
+        tracing::info!(
+            "file cleanup scanned {} blobs ({} live, {} recent), deleted {}, failed {}, removed {} stale temporary files, took {:?}",
+            stats.scanned,
+            stats.live,
+            stats.recent,
+            stats.deleted,
+            stats.failed,
+            stats.stale_temp,
+            stats.elapsed
+        );
+    }
+    return Ok(stats);
+}
+
+/// Runs the url and the file cleanup once.
+pub async fn gc(db: &dyn Repository) -> Result<CleanupStats, AppError> {
+    cleanup_urls(db).await?;
+    return cleanup_files(db, &CancellationToken::new()).await;
+}
+
+/// Records the sizes of blobs uploaded by older versions, which didn't.
+pub async fn backfill_file_sizes(db: &dyn Repository) -> Result<(), AppError> {
+    let mut sizes = Vec::new();
+    for name in db.list_files_without_size().await? {
+        if let Some(size) = storage().size(&name).await? {
+            sizes.push((name, size));
+        }
+    }
+    if sizes.is_empty() {
+        return Ok(());
+    }
+
+    tracing::info!("recording the size of {} blobs", sizes.len());
+    return db.set_file_sizes(sizes).await;
+}
+
+/// Re-hashes every blob, corrupted ones are quarantined and their urls dropped.
+/// Stops early once `shutdown` is cancelled, as a scrub may take hours.
+async fn scrub_files(db: &dyn Repository, shutdown: &CancellationToken) -> Result<(), AppError> {
+    let mut corrupted = 0;
+    let mut scrubbed = 0;
+    for (name, encoding) in &db.list_files().await? {
+        if shutdown.is_cancelled() {
+            break;
+        }
+        scrubbed += 1;
+        let Some(encoding) = Encoding::parse(encoding) else {
+            tracing::error!("blob {} has unknown encoding '{}'", name, encoding);
+            continue;
+        };
+        match verify_blob(name, encoding).await {
+            Ok(true) => (),
+            Ok(false) => match quarantine_if_corrupted(db, name).await {
+                Ok(true) => corrupted += 1,
+                Ok(false) => (),
+                Err(e) => tracing::error!("cannot quarantine blob {}: {}", name, e),
+            },
+            Err(e) => tracing::warn!("cannot scrub blob {}: {}", name, e),
+        }
+    }
+
+    if corrupted > 0 {
+        tracing::error!("scrub found {} corrupted blobs", corrupted);
+    }
+    tracing::info!("scrubbed {} blobs", scrubbed);
     return Ok(());
 }
 
-fn init_cleanup_urls(db_pool: Arc<Pool>) {
-    tokio::task::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(conf().cleanup_urls_duration));
+/// Verifies a blob that failed once again with uploads held off, as an upload
+/// may have rewritten it in another encoding since the blobs were listed, and
+/// quarantines it together with its urls if it's still corrupted. Returns
+/// whether it was quarantined.
+async fn quarantine_if_corrupted(db: &dyn Repository, name: &str) -> Result<bool, AppError> {
+    let _guard = BLOB_LOCK.write().await;
+    let Some(encoding) = db.get_file_encoding(name).await? else {
+        return Ok(false);
+    };
+    let Some(encoding) = Encoding::parse(&encoding) else {
+        tracing::error!("blob {} has unknown encoding '{}'", name, encoding);
+        return Ok(false);
+    };
+    if verify_blob(name, encoding).await? {
+        return Ok(false);
+    }
+    tracing::error!("blob {} doesn't match its sha256sum, quarantining it", name);
+    storage().quarantine(name).await?;
+    // right away, before its urls fail or a new upload of it is committed
+    let removed = db.remove_files(vec![name.to_string()]).await?;
+    tracing::error!("removed {} urls of corrupted blob {}", removed, name);
+    return Ok(true);
+}
+
+/// Waits until the interval `interval` reads from the current config has
+/// passed since `since`, starting over with the new interval after a reload.
+/// An interval of 0 waits for a reload. Returns false on shutdown.
+async fn wait_interval(
+    since: Instant,
+    interval: fn(&Config) -> u64,
+    shutdown: &CancellationToken,
+) -> bool {
+    loop {
+        let reloaded = CONFIG_RELOADED.notified();
+        let secs = interval(&conf());
+        tokio::select! {
+            _ = shutdown.cancelled() => return false,
+            _ = reloaded => continue,
+            _ = tokio::time::sleep_until((since + Duration::from_secs(secs)).into()), if secs > 0 => {
+                return true;
+            }
+        }
+    }
+}
+
+fn init_cleanup_urls(db: Db, shutdown: CancellationToken) -> JoinHandle<()> {
+    return tokio::task::spawn(async move {
         loop {
-            interval.tick().await;
-            match cleanup_urls(&db_pool).await {
+            let started = Instant::now();
+            match cleanup_urls(db.as_ref()).await {
                 Ok(_) => (),
                 Err(e) => tracing::error!("{}", e),
             };
+            if !wait_interval(started, |c| c.cleanup_urls_duration, &shutdown).await {
+                break;
+            }
         }
     });
 }
 
-fn init_cleanup_files(db_pool: Arc<Pool>) {
-    tokio::task::spawn(async move {
-        let mut interval =
-            tokio::time::interval(Duration::from_secs(conf().cleanup_files_duration));
+fn init_cleanup_files(db: Db, shutdown: CancellationToken) -> JoinHandle<()> {
+    return tokio::task::spawn(async move {
         loop {
-            interval.tick().await;
-            match cleanup_files(&db_pool).await {
+            let started = Instant::now();
+            match cleanup_files(db.as_ref(), &shutdown).await {
+                Ok(_) => (),
+                Err(e) => tracing::error!("{}", e),
+            };
+            if !wait_interval(started, |c| c.cleanup_files_duration, &shutdown).await {
+                break;
+            }
+        }
+    });
+}
+
+fn init_scrub(db: Db, shutdown: CancellationToken) -> JoinHandle<()> {
+    return tokio::task::spawn(async move {
+        // wait a full interval first, a scrub reads every blob
+        let mut started = Instant::now();
+        while wait_interval(started, |c| c.scrub_duration, &shutdown).await {
+            started = Instant::now();
+            match scrub_files(db.as_ref(), &shutdown).await {
                 Ok(_) => (),
                 Err(e) => tracing::error!("{}", e),
             };
@@ -46,7 +281,12 @@ fn init_cleanup_files(db_pool: Arc<Pool>) {
     });
 }
 
-pub fn init_cleanup(db_pool: &Arc<Pool>) {
-    init_cleanup_urls(db_pool.clone());
-    init_cleanup_files(db_pool.clone());
+/// Spawns the background tasks, they stop once `shutdown` is cancelled,
+/// letting a pass in progress finish first.
+pub fn init_cleanup(db: &Db, shutdown: &CancellationToken) -> Vec<JoinHandle<()>> {
+    return vec![
+        init_cleanup_urls(db.clone(), shutdown.clone()),
+        init_cleanup_files(db.clone(), shutdown.clone()),
+        init_scrub(db.clone(), shutdown.clone()),
+    ];
 }
@@ -0,0 +1,239 @@
+use crate::conf;
+use crate::db::UrlFile;
+use crate::error::AppError;
+use crate::storage::{ByteStream, storage};
+
+use std::io::ErrorKind;
+
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use axum::body::Bytes;
+use axum::http::{Extensions, HeaderMap, StatusCode, Version, header};
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tower_http::compression::{CompressionLayer, predicate::Predicate};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// The name used in the `files` table as well as in `Content-Encoding`.
+    pub fn as_str(self) -> &'static str {
+        return match self {
+            Encoding::Identity => "",
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        };
+    }
+
+    pub fn parse(s: &str) -> Option<Encoding> {
+        return match s {
+            "" | "identity" | "none" => Some(Encoding::Identity),
+            "gzip" => Some(Encoding::Gzip),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        };
+    }
+
+    pub fn of_file(file: &UrlFile) -> Result<Encoding, AppError> {
+        return Encoding::parse(&file.encoding).ok_or(AppError::Storage(format!(
+            "blob {} has unknown encoding '{}'",
+            file.file_sha256sum, file.encoding
+        )));
+    }
+
+    pub async fn encode(self, data: &[u8]) -> Result<Vec<u8>, AppError> {
+        let mut encoded = Vec::new();
+        match self {
+            Encoding::Identity => encoded.extend_from_slice(data),
+            Encoding::Gzip => {
+                GzipEncoder::new(data).read_to_end(&mut encoded).await?;
+            }
+            Encoding::Zstd => {
+                ZstdEncoder::new(data).read_to_end(&mut encoded).await?;
+            }
+        };
+        return Ok(encoded);
+    }
+
+    pub fn decode_stream(self, stream: ByteStream) -> ByteStream {
+        let reader = StreamReader::new(stream);
+        return match self {
+            Encoding::Identity => Box::pin(ReaderStream::new(reader)),
+            Encoding::Gzip => Box::pin(ReaderStream::new(GzipDecoder::new(reader))),
+            Encoding::Zstd => Box::pin(ReaderStream::new(ZstdDecoder::new(reader))),
+        };
+    }
+
+    /// Whether the client listed this encoding in `Accept-Encoding` without `q=0`.
+    pub fn accepted_by(self, headers: &HeaderMap) -> bool {
+        if self == Encoding::Identity {
+            return true;
+        }
+        return headers
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|item| {
+                let mut parts = item.split(';').map(|p| p.trim());
+                let coding = parts.next().unwrap_or_default();
+                let rejected = parts.any(|p| {
+                    p.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.)
+                });
+                return (coding.eq_ignore_ascii_case(self.as_str()) || coding == "*") && !rejected;
+            });
+    }
+}
+
+fn is_compressible(mimetype: &str) -> bool {
+    return conf()
+        .compression_mimetypes
+        .iter()
+        .any(|prefix| mimetype.starts_with(prefix.as_str()));
+}
+
+/// Compresses the content with the configured at-rest encoding if its
+/// mimetype is listed and compression actually saves space.
+pub async fn compress_for_storage(
+    data: &Bytes,
+    mimetype: &str,
+) -> Result<(Bytes, Encoding), AppError> {
+    let c = conf();
+    if c.compression_at_rest == Encoding::Identity || !is_compressible(mimetype) {
+        return Ok((data.clone(), Encoding::Identity));
+    }
+
+    let encoded = c.compression_at_rest.encode(data).await?;
+    if encoded.len() >= data.len() {
+        return Ok((data.clone(), Encoding::Identity));
+    }
+    return Ok((Bytes::from(encoded), c.compression_at_rest));
+}
+
+/// Whether the decoded content of a blob still hashes to its name. Content
+/// the decoder rejects counts as a mismatch.
+pub async fn verify_blob(name: &str, encoding: Encoding) -> Result<bool, AppError> {
+    let (stream, _) = storage().stream(name).await?;
+    return content_matches(name, encoding, stream).await;
+}
+
+/// Like `verify_blob`, for a blob that isn't stored yet.
+pub async fn verify_data(name: &str, encoding: Encoding, data: Bytes) -> Result<bool, AppError> {
+    let stream = futures_util::stream::once(async move { Ok(data) });
+    return content_matches(name, encoding, Box::pin(stream)).await;
+}
+
+async fn content_matches(
+    name: &str,
+    encoding: Encoding,
+    stream: ByteStream,
+) -> Result<bool, AppError> {
+    let mut stream = encoding.decode_stream(stream);
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) => hasher.update(&chunk),
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => {
+                return Ok(false);
+            }
+            Err(e) => return Err(AppError::IO(e)),
+        }
+    }
+    return Ok(hex::encode(hasher.finalize()) == name);
+}
+
+/// With `verify_on_read` set, fails with `BlobCorrupted` unless the blob of
+/// the url matches its name.
+pub async fn check_on_read(file: &UrlFile) -> Result<(), AppError> {
+    if !conf().verify_on_read {
+        return Ok(());
+    }
+    let encoding = Encoding::of_file(file)?;
+    if !verify_blob(&file.file_sha256sum, encoding).await? {
+        return Err(AppError::BlobCorrupted(file.file_sha256sum.clone()));
+    }
+    return Ok(());
+}
+
+/// Opens the blob of a url as its original content, returns the stream and
+/// the content size if known.
+pub async fn open_decoded(file: &UrlFile) -> Result<(ByteStream, Option<u64>), AppError> {
+    check_on_read(file).await?;
+    let encoding = Encoding::of_file(file)?;
+    let (stream, stored_size) = storage().stream(&file.file_sha256sum).await?;
+    return match encoding {
+        Encoding::Identity => Ok((stream, Some(stored_size))),
+        _ => Ok((encoding.decode_stream(stream), file.size)),
+    };
+}
+
+/// Responses of compressible mimetypes, at least `response_compression_min_size`
+/// long, are compressed with whatever listed algorithm the client prefers.
+/// Responses already carrying a `Content-Encoding` are left alone.
+pub fn response_compression_layer() -> CompressionLayer<impl Predicate> {
+    let algorithms = &conf().response_compression;
+    let enabled = |name: &str| algorithms.iter().any(|a| a == name);
+    let predicate = |_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions| {
+        let mimetype = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let size = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        return is_compressible(mimetype)
+            && size.is_none_or(|size| size >= conf().response_compression_min_size);
+    };
+    return CompressionLayer::new()
+        .gzip(enabled("gzip"))
+        .br(enabled("br"))
+        .zstd(enabled("zstd"))
+        .no_deflate()
+        .compress_when(predicate);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept_encoding(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::ACCEPT_ENCODING, value.parse().unwrap());
+        }
+        return headers;
+    }
+
+    #[test]
+    fn identity_is_always_accepted() {
+        assert!(Encoding::Identity.accepted_by(&HeaderMap::new()));
+        assert!(Encoding::Identity.accepted_by(&accept_encoding(&["identity;q=0"])));
+    }
+
+    #[test]
+    fn accepted_by_reads_every_header_and_item() {
+        let headers = accept_encoding(&["br, GZIP;q=0.5", "deflate"]);
+        assert!(Encoding::Gzip.accepted_by(&headers));
+        assert!(!Encoding::Zstd.accepted_by(&headers));
+        assert!(!Encoding::Gzip.accepted_by(&HeaderMap::new()));
+        assert!(!Encoding::Zstd.accepted_by(&accept_encoding(&["zstdx"])));
+    }
+
+    #[test]
+    fn accepted_by_honors_q_zero_and_wildcards() {
+        assert!(!Encoding::Gzip.accepted_by(&accept_encoding(&["gzip;q=0"])));
+        assert!(!Encoding::Gzip.accepted_by(&accept_encoding(&["gzip; q=0.000"])));
+        assert!(Encoding::Gzip.accepted_by(&accept_encoding(&["gzip;q=0.001"])));
+        assert!(Encoding::Zstd.accepted_by(&accept_encoding(&["*"])));
+        assert!(!Encoding::Zstd.accepted_by(&accept_encoding(&["*;q=0"])));
+    }
+}
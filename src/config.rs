@@ -1,23 +1,89 @@
 // default configs
-const LISTEN_ADDR: &'static str = "127.0.0.1:3000";
-const BASE_URL: &'static str = "http://127.0.0.1:3000";
-const UPLOAD_FILE_DIR: &'static str = "./uploads";
-const DATABASE_FILE: &'static str = "webpaste.db";
+const LISTEN_ADDR: &str = "127.0.0.1:3000";
+const BASE_URL: &str = "http://127.0.0.1:3000";
+const UPLOAD_FILE_DIR: &str = "./uploads";
+const DATABASE_FILE: &str = "webpaste.db";
+const SQLITE_POOL_SIZE: usize = 8;
+const SQLITE_SYNCHRONOUS: &str = "normal";
+const SQLITE_BUSY_TIMEOUT: u64 = 5000;
 const GEN_TAIL_MAX_ATTAMPS: usize = 16;
 const DEFAULT_TAIL_LEN: usize = 4;
+/// Longest tail an upload may ask for, tails are drawn from 52 letters so
+/// even far shorter ones can't run out.
+pub const MAX_TAIL_LEN: usize = 64;
 const MIN_EXPIRE_AGE: i64 = 30 * 24 * 60 * 60;
 const MAX_EXPIRE_AGE: i64 = 365 * 24 * 60 * 60;
 const MAX_FILE_SIZE: usize = 512 * 1024 * 1024;
+const MAX_BATCH_FILES: usize = 32;
+const MAX_BATCH_SIZE: usize = 1024 * 1024 * 1024;
 const CLEANUP_URLS_DURATION: u64 = 30;
 const CLEANUP_FILES_DURATION: u64 = 60;
+const CLEANUP_GRACE_PERIOD: u64 = 60 * 60;
+const SCRUB_DURATION: u64 = 24 * 60 * 60;
+const SHUTDOWN_TIMEOUT: u64 = 30;
+const S3_REGION: &str = "us-east-1";
+const RESPONSE_COMPRESSION: [&str; 3] = ["zstd", "br", "gzip"];
+const RESPONSE_COMPRESSION_MIN_SIZE: usize = 1024;
+const COMPRESSION_MIMETYPES: [&str; 5] = [
+    "text/",
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "image/svg+xml",
+];
 
 use std::path::{Path, PathBuf};
 
+use crate::compression::Encoding;
 use crate::error::AppError;
+use crate::utils::parse_size;
+use arc_swap::ArcSwap;
 use serde::Deserialize;
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Notify;
+
+/// Every key of the config file, in the order of `ConfigFile`.
+const KEYS: [&str; 36] = [
+    "listen_addr",
+    "base_url",
+    "upload_file_dir",
+    "database_file",
+    "database_url",
+    "sqlite_pool_size",
+    "sqlite_wal",
+    "sqlite_synchronous",
+    "sqlite_busy_timeout",
+    "gen_tail_max_attamps",
+    "default_tail_len",
+    "min_expire_duration",
+    "max_expire_duration",
+    "max_file_size",
+    "max_batch_files",
+    "max_batch_size",
+    "max_total_storage",
+    "eviction_policy",
+    "cleanup_urls_duration",
+    "cleanup_files_duration",
+    "cleanup_grace_period",
+    "scrub_duration",
+    "verify_on_read",
+    "shutdown_timeout",
+    "storage_backend",
+    "s3_endpoint",
+    "s3_bucket",
+    "s3_region",
+    "s3_access_key",
+    "s3_secret_key",
+    "s3_prefix",
+    "compression_at_rest",
+    "compression_mimetypes",
+    "response_compression",
+    "response_compression_min_size",
+    "retention_rules",
+];
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct ConfigFile {
     #[serde(default)]
     listen_addr: Option<String>,
@@ -28,6 +94,16 @@ struct ConfigFile {
     #[serde(default)]
     database_file: Option<String>,
     #[serde(default)]
+    database_url: Option<String>,
+    #[serde(default)]
+    sqlite_pool_size: Option<i64>,
+    #[serde(default)]
+    sqlite_wal: Option<bool>,
+    #[serde(default)]
+    sqlite_synchronous: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_humantime_millis")]
+    sqlite_busy_timeout: Option<i64>,
+    #[serde(default)]
     gen_tail_max_attamps: Option<i64>,
     #[serde(default)]
     default_tail_len: Option<i64>,
@@ -35,101 +111,807 @@ struct ConfigFile {
     min_expire_duration: Option<i64>,
     #[serde(default, deserialize_with = "deserialize_humantime_duration")]
     max_expire_duration: Option<i64>,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_size")]
     max_file_size: Option<i64>,
+    #[serde(default)]
+    max_batch_files: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    max_batch_size: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    max_total_storage: Option<i64>,
+    #[serde(default)]
+    eviction_policy: Option<String>,
     #[serde(default, deserialize_with = "deserialize_humantime_duration")]
     cleanup_urls_duration: Option<i64>,
     #[serde(default, deserialize_with = "deserialize_humantime_duration")]
     cleanup_files_duration: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_humantime_duration")]
+    cleanup_grace_period: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_humantime_duration")]
+    scrub_duration: Option<i64>,
+    #[serde(default)]
+    verify_on_read: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_humantime_duration")]
+    shutdown_timeout: Option<i64>,
+    #[serde(default)]
+    storage_backend: Option<String>,
+    #[serde(default)]
+    s3_endpoint: Option<String>,
+    #[serde(default)]
+    s3_bucket: Option<String>,
+    #[serde(default)]
+    s3_region: Option<String>,
+    #[serde(default)]
+    s3_access_key: Option<String>,
+    #[serde(default)]
+    s3_secret_key: Option<String>,
+    #[serde(default)]
+    s3_prefix: Option<String>,
+    #[serde(default)]
+    compression_at_rest: Option<String>,
+    #[serde(default)]
+    compression_mimetypes: Option<Vec<String>>,
+    #[serde(default)]
+    response_compression: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    response_compression_min_size: Option<i64>,
+    #[serde(default)]
+    retention_rules: Option<Vec<RetentionRuleFile>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RetentionRuleFile {
+    #[serde(default)]
+    mimetype: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    min_size: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_humantime_duration")]
+    retention: Option<i64>,
 }
 
 fn deserialize_humantime_duration<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    let s: Option<String> = Option::deserialize(deserializer)?;
-    match s {
-        Some(s) => humantime::parse_duration(&s)
-            .map(|d| Some(d.as_secs() as i64))
-            .map_err(|e| serde::de::Error::custom(e)),
+    return deserialize_humantime(deserializer, 1000).map(|millis| millis.map(|m| m / 1000));
+}
+
+/// Like `deserialize_humantime_duration` but in milliseconds, plain numbers
+/// are milliseconds too.
+fn deserialize_humantime_millis<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    return deserialize_humantime(deserializer, 1);
+}
+
+/// Reads a duration like `1h 30m` or a plain number of `unit_millis`
+/// milliseconds, returns it in milliseconds.
+fn deserialize_humantime<'de, D>(deserializer: D, unit_millis: i64) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    /// plain numbers are taken as well, as environment variables like `30` read as integers
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Duration {
+        Number(i64),
+        Human(String),
+    }
+
+    match Option::<Duration>::deserialize(deserializer)? {
+        Some(Duration::Number(n)) => Ok(Some(n.saturating_mul(unit_millis))),
+        Some(Duration::Human(s)) => humantime::parse_duration(&s)
+            .map(|d| Some(d.as_millis() as i64))
+            .map_err(serde::de::Error::custom),
         None => Ok(None),
     }
 }
 
+/// Sizes are bytes or strings like `512MiB`, see `parse_size`.
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(i64),
+        Human(String),
+    }
+
+    match Option::<Size>::deserialize(deserializer)? {
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::Human(s)) => parse_size(&s)
+            .map(|size| Some(size as i64))
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    Fs,
+    S3,
+}
+
+/// Which urls make room when `max_total_storage` is reached.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// reject the upload instead
+    None,
+    /// urls closest to expiry first
+    Expiry,
+    /// least recently accessed urls first
+    Lru,
+}
+
+/// Overrides the retention curve for uploads it matches, all of its
+/// conditions have to hold.
+pub struct RetentionRule {
+    /// mimetype prefix, like `image/`
+    pub mimetype: Option<String>,
+    /// smallest size in bytes
+    pub min_size: Option<usize>,
+    /// seconds an upload is kept
+    pub retention: i64,
+}
+
+impl RetentionRule {
+    pub fn matches(&self, mimetype: &str, size: usize) -> bool {
+        return self
+            .mimetype
+            .as_ref()
+            .is_none_or(|prefix| mimetype.starts_with(prefix.as_str()))
+            && self.min_size.is_none_or(|min_size| size >= min_size);
+    }
+}
+
 pub struct Config {
     pub listen_addr: String,
     pub base_url: String,
     pub upload_file_dir: PathBuf,
     pub database_file: PathBuf,
+    /// `postgres://` connection url, SQLite at `database_file` is used if empty.
+    /// TLS is used if the server offers it, `sslmode=require` insists on it and
+    /// `sslmode=disable` turns it off. Certificates must be trusted by the system
+    pub database_url: String,
+    pub sqlite_pool_size: usize,
+    /// use write-ahead logging so readers don't block on writers
+    pub sqlite_wal: bool,
+    /// `PRAGMA synchronous` level, one of off, normal, full and extra
+    pub sqlite_synchronous: String,
+    /// milliseconds a connection waits on a locked database before failing
+    pub sqlite_busy_timeout: u64,
     pub gen_tail_max_attamps: usize,
     pub default_tail_len: usize,
     pub min_expire_duration: i64,
     pub max_expire_duration: i64,
     pub max_file_size: usize,
+    /// files, including fetched urls, a single upload may contain
+    pub max_batch_files: usize,
+    /// bytes all files of a single upload may take together
+    pub max_batch_size: usize,
+    /// bytes all blobs may take together, 0 for no limit
+    pub max_total_storage: u64,
+    pub eviction_policy: EvictionPolicy,
     pub cleanup_urls_duration: u64,
     pub cleanup_files_duration: u64,
+    /// seconds an unreferenced blob is kept after it was last written, so
+    /// the file cleanup leaves blobs of in-flight uploads alone
+    pub cleanup_grace_period: u64,
+    /// how often every blob is re-hashed, 0 disables the scrub
+    pub scrub_duration: u64,
+    /// re-hash blobs before serving them
+    pub verify_on_read: bool,
+    /// seconds in-flight requests get to finish on shutdown
+    pub shutdown_timeout: u64,
+    pub storage_backend: StorageBackend,
+    pub s3_endpoint: String,
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    pub s3_prefix: String,
+    pub compression_at_rest: Encoding,
+    /// mimetype prefixes compressed at rest and in responses
+    pub compression_mimetypes: Vec<String>,
+    /// algorithms offered for response compression, empty to disable it
+    pub response_compression: Vec<String>,
+    pub response_compression_min_size: usize,
+    /// tried in order before the retention curve, the first match decides
+    pub retention_rules: Vec<RetentionRule>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            listen_addr: LISTEN_ADDR.to_string(),
-            base_url: BASE_URL.to_string(),
-            upload_file_dir: PathBuf::from(UPLOAD_FILE_DIR),
-            database_file: PathBuf::from(DATABASE_FILE),
-            gen_tail_max_attamps: GEN_TAIL_MAX_ATTAMPS,
-            default_tail_len: DEFAULT_TAIL_LEN,
-            min_expire_duration: MIN_EXPIRE_AGE,
-            max_expire_duration: MAX_EXPIRE_AGE,
-            max_file_size: MAX_FILE_SIZE,
-            cleanup_urls_duration: CLEANUP_URLS_DURATION,
-            cleanup_files_duration: CLEANUP_FILES_DURATION,
-        }
+fn is_http_url(url: &str) -> bool {
+    return match url.split_once("://") {
+        Some(("http" | "https", rest)) => !rest.is_empty() && !rest.starts_with('/'),
+        _ => false,
+    };
+}
+
+/// Checks that files can be created in `dir`, or that it can be created if it
+/// doesn't exist yet, without creating anything that stays behind.
+fn check_writable(dir: &Path) -> Result<(), std::io::Error> {
+    let Some(dir) = dir
+        .ancestors()
+        .map(|dir| match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        })
+        .find(|dir| dir.exists())
+    else {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+    };
+    if !dir.is_dir() {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotADirectory));
     }
+    let probe = dir.join(format!(".webpaste-probe-{}", std::process::id()));
+    std::fs::write(&probe, b"")?;
+    return std::fs::remove_file(&probe);
 }
 
-fn read_config(path: &Path) -> Result<Config, AppError> {
-    let config_str = std::fs::read_to_string(path)?;
-    let c = toml::from_str::<ConfigFile>(&config_str)
-        .map_err(|e| AppError::ConfigParseError(e.to_string()))?;
+/// Environment variables named like this followed by a config key in upper
+/// case override the config file, e.g. `WEBPASTE_MAX_FILE_SIZE=1048576`.
+const ENV_PREFIX: &str = "WEBPASTE_";
+
+/// Keys holding a plain string, their override values are taken verbatim so
+/// e.g. a bucket named `1234` stays a string.
+const STRING_KEYS: [&str; 15] = [
+    "listen_addr",
+    "base_url",
+    "upload_file_dir",
+    "database_file",
+    "database_url",
+    "sqlite_synchronous",
+    "eviction_policy",
+    "storage_backend",
+    "s3_endpoint",
+    "s3_bucket",
+    "s3_region",
+    "s3_access_key",
+    "s3_secret_key",
+    "s3_prefix",
+    "compression_at_rest",
+];
+
+/// Reads an override value for `key`, verbatim for string keys and as TOML
+/// otherwise, falling back to a plain string if it isn't valid TOML, so both
+/// `1024` and `1MiB` work. Lists take TOML syntax, e.g. `["gzip", "zstd"]`.
+fn parse_value(key: &str, value: &str) -> toml::Value {
+    if STRING_KEYS.contains(&key) {
+        return toml::Value::String(value.to_string());
+    }
+    return match toml::from_str::<toml::Table>(&format!("v = {}", value)) {
+        Ok(mut table) => table.remove("v").unwrap(),
+        Err(_) => toml::Value::String(value.to_string()),
+    };
+}
+
+/// Parses a `key=value` override given on the command line.
+pub fn parse_override(s: &str) -> Result<(String, toml::Value), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or(format!("expected KEY=VALUE, got '{}'", s))?;
+    let key = key.trim();
+    return Ok((key.to_string(), parse_value(key, value)));
+}
+
+/// Overrides from `WEBPASTE_*` variables, variables not named after a config
+/// key are ignored, like `WEBPASTE_CONFIG` naming the config file.
+fn env_overrides() -> Vec<(String, toml::Value)> {
+    return std::env::vars()
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(ENV_PREFIX)?.to_lowercase();
+            if !KEYS.contains(&key.as_str()) {
+                return None;
+            }
+            let value = parse_value(&key, &value);
+            return Some((key, value));
+        })
+        .collect();
+}
+
+/// Reads the config file, if any. `WEBPASTE_*` variables take precedence
+/// over the file and `overrides` over both, values set nowhere take the
+/// compiled-in defaults.
+fn read_config(
+    path: Option<&Path>,
+    overrides: &[(String, toml::Value)],
+) -> Result<Config, AppError> {
+    let mut table = match path {
+        Some(path) => toml::from_str::<toml::Table>(&std::fs::read_to_string(path)?)
+            .map_err(|e| AppError::ConfigParseError(vec![e.to_string()]))?,
+        None => toml::Table::new(),
+    };
+    for (key, value) in env_overrides().iter().chain(overrides) {
+        table.insert(key.clone(), value.clone());
+    }
+
+    // deserialized one key at a time so every unknown or mistyped key is
+    // reported, they are left out of the rest so their other errors are found too
+    let mut errors = Vec::new();
+    table.retain(|key, value| {
+        if !KEYS.contains(&key) {
+            errors.push(format!("unknown key {}", key));
+            return false;
+        }
+        let single = toml::Table::from_iter([(key.to_string(), value.clone())]);
+        return match toml::Value::Table(single).try_into::<ConfigFile>() {
+            Ok(_) => true,
+            Err(e) => {
+                errors.push(e.to_string().trim().replace('\n', " "));
+                false
+            }
+        };
+    });
+    let c = toml::Value::Table(table)
+        .try_into::<ConfigFile>()
+        .map_err(|e| AppError::ConfigParseError(vec![e.to_string()]))?;
+    let mut at_least = |key: &str, value: Option<i64>, min: i64, default: i64| -> i64 {
+        let value = value.unwrap_or(default);
+        if value < min {
+            errors.push(format!("{} must be at least {}, got {}", key, min, value));
+        }
+        return value;
+    };
+
+    let sqlite_pool_size = at_least(
+        "sqlite_pool_size",
+        c.sqlite_pool_size,
+        1,
+        SQLITE_POOL_SIZE as i64,
+    );
+    let sqlite_busy_timeout = at_least(
+        "sqlite_busy_timeout",
+        c.sqlite_busy_timeout,
+        0,
+        SQLITE_BUSY_TIMEOUT as i64,
+    );
+    let gen_tail_max_attamps = at_least(
+        "gen_tail_max_attamps",
+        c.gen_tail_max_attamps,
+        1,
+        GEN_TAIL_MAX_ATTAMPS as i64,
+    );
+    let default_tail_len = at_least(
+        "default_tail_len",
+        c.default_tail_len,
+        1,
+        DEFAULT_TAIL_LEN as i64,
+    );
+    let min_expire_duration = at_least(
+        "min_expire_duration",
+        c.min_expire_duration,
+        0,
+        MIN_EXPIRE_AGE,
+    );
+    let max_expire_duration = at_least(
+        "max_expire_duration",
+        c.max_expire_duration,
+        0,
+        MAX_EXPIRE_AGE,
+    );
+    let max_file_size = at_least("max_file_size", c.max_file_size, 1, MAX_FILE_SIZE as i64);
+    let max_batch_files = at_least(
+        "max_batch_files",
+        c.max_batch_files,
+        1,
+        MAX_BATCH_FILES as i64,
+    );
+    let max_batch_size = at_least("max_batch_size", c.max_batch_size, 1, MAX_BATCH_SIZE as i64);
+    let max_total_storage = at_least("max_total_storage", c.max_total_storage, 0, 0);
+    let cleanup_urls_duration = at_least(
+        "cleanup_urls_duration",
+        c.cleanup_urls_duration,
+        1,
+        CLEANUP_URLS_DURATION as i64,
+    );
+    let cleanup_files_duration = at_least(
+        "cleanup_files_duration",
+        c.cleanup_files_duration,
+        1,
+        CLEANUP_FILES_DURATION as i64,
+    );
+    let cleanup_grace_period = at_least(
+        "cleanup_grace_period",
+        c.cleanup_grace_period,
+        0,
+        CLEANUP_GRACE_PERIOD as i64,
+    );
+    let scrub_duration = at_least("scrub_duration", c.scrub_duration, 0, SCRUB_DURATION as i64);
+    let shutdown_timeout = at_least(
+        "shutdown_timeout",
+        c.shutdown_timeout,
+        0,
+        SHUTDOWN_TIMEOUT as i64,
+    );
+    let response_compression_min_size = at_least(
+        "response_compression_min_size",
+        c.response_compression_min_size,
+        0,
+        RESPONSE_COMPRESSION_MIN_SIZE as i64,
+    );
+
+    if default_tail_len > MAX_TAIL_LEN as i64 {
+        errors.push(format!(
+            "default_tail_len must be at most {}, got {}",
+            MAX_TAIL_LEN, default_tail_len
+        ));
+    }
+    if min_expire_duration > max_expire_duration {
+        errors.push(format!(
+            "min_expire_duration ({}s) must not exceed max_expire_duration ({}s)",
+            min_expire_duration, max_expire_duration
+        ));
+    }
+
+    let base_url = c
+        .base_url
+        .unwrap_or(BASE_URL.to_string())
+        .trim_end_matches('/')
+        .to_string();
+    if !is_http_url(&base_url) {
+        errors.push(format!(
+            "base_url '{}' must be an http:// or https:// url",
+            base_url
+        ));
+    }
+
+    let storage_backend = match c.storage_backend.as_deref() {
+        None | Some("fs") => StorageBackend::Fs,
+        Some("s3") => StorageBackend::S3,
+        Some(other) => {
+            errors.push(format!(
+                "unknown storage_backend '{}', expected 'fs' or 's3'",
+                other
+            ));
+            StorageBackend::Fs
+        }
+    };
+    let upload_file_dir = PathBuf::from(c.upload_file_dir.unwrap_or(UPLOAD_FILE_DIR.to_string()));
+    match storage_backend {
+        StorageBackend::Fs => {
+            if let Err(e) = check_writable(&upload_file_dir) {
+                errors.push(format!(
+                    "upload_file_dir {} is not writable: {}",
+                    upload_file_dir.display(),
+                    e
+                ));
+            }
+        }
+        StorageBackend::S3 => match &c.s3_endpoint {
+            Some(endpoint) if !is_http_url(endpoint) => errors.push(format!(
+                "s3_endpoint '{}' must be an http:// or https:// url",
+                endpoint
+            )),
+            None => errors.push("s3 storage_backend needs s3_endpoint".to_string()),
+            _ => (),
+        },
+    }
+    if matches!(storage_backend, StorageBackend::S3) && c.s3_bucket.is_none() {
+        errors.push("s3 storage_backend needs s3_bucket".to_string());
+    }
+
+    let database_file = PathBuf::from(c.database_file.unwrap_or(DATABASE_FILE.to_string()));
+    match &c.database_url {
+        Some(url) if !url.starts_with("postgres://") && !url.starts_with("postgresql://") => {
+            errors.push(format!(
+                "unsupported database_url '{}', expected a postgres:// url",
+                url
+            ));
+        }
+        Some(_) => (),
+        None => {
+            let dir = match database_file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if let Err(e) = check_writable(dir) {
+                errors.push(format!(
+                    "directory of database_file {} is not writable: {}",
+                    database_file.display(),
+                    e
+                ));
+            }
+        }
+    }
+
+    let sqlite_synchronous = c
+        .sqlite_synchronous
+        .unwrap_or(SQLITE_SYNCHRONOUS.to_string())
+        .to_lowercase();
+    if !["off", "normal", "full", "extra"].contains(&sqlite_synchronous.as_str()) {
+        errors.push(format!(
+            "unknown sqlite_synchronous '{}', expected 'off', 'normal', 'full' or 'extra'",
+            sqlite_synchronous
+        ));
+    }
+    let eviction_policy = match c.eviction_policy.as_deref() {
+        None | Some("none") => EvictionPolicy::None,
+        Some("expiry") => EvictionPolicy::Expiry,
+        Some("lru") => EvictionPolicy::Lru,
+        Some(other) => {
+            errors.push(format!(
+                "unknown eviction_policy '{}', expected 'none', 'expiry' or 'lru'",
+                other
+            ));
+            EvictionPolicy::None
+        }
+    };
+    let compression_at_rest = match c.compression_at_rest.as_deref() {
+        None => Encoding::Identity,
+        Some(s) => Encoding::parse(s).unwrap_or_else(|| {
+            errors.push(format!(
+                "unknown compression_at_rest '{}', expected 'none', 'gzip' or 'zstd'",
+                s
+            ));
+            Encoding::Identity
+        }),
+    };
+    let response_compression = c
+        .response_compression
+        .unwrap_or(RESPONSE_COMPRESSION.map(|s| s.to_string()).to_vec());
+    for other in response_compression
+        .iter()
+        .filter(|a| !RESPONSE_COMPRESSION.contains(&a.as_str()))
+    {
+        errors.push(format!(
+            "unknown response_compression algorithm '{}', expected 'zstd', 'br' or 'gzip'",
+            other
+        ));
+    }
+
+    let mut retention_rules = Vec::new();
+    for (i, rule) in c
+        .retention_rules
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+    {
+        if rule.mimetype.is_none() && rule.min_size.is_none() {
+            errors.push(format!(
+                "retention_rules[{}] needs a mimetype or a min_size",
+                i
+            ));
+        }
+        if rule.min_size.is_some_and(|min_size| min_size < 0) {
+            errors.push(format!(
+                "retention_rules[{}].min_size must not be negative",
+                i
+            ));
+        }
+        match rule.retention {
+            Some(retention) if retention > 0 => retention_rules.push(RetentionRule {
+                mimetype: rule.mimetype,
+                min_size: rule.min_size.map(|min_size| min_size as usize),
+                retention,
+            }),
+            Some(retention) => errors.push(format!(
+                "retention_rules[{}].retention must be at least 1, got {}",
+                i, retention
+            )),
+            None => errors.push(format!("retention_rules[{}] needs a retention", i)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(AppError::ConfigParseError(errors));
+    }
+
     return Ok(Config {
         listen_addr: c.listen_addr.unwrap_or(LISTEN_ADDR.to_string()),
-        base_url: c.base_url.unwrap_or(BASE_URL.to_string()),
-        upload_file_dir: PathBuf::from(c.upload_file_dir.unwrap_or(UPLOAD_FILE_DIR.to_string())),
-        database_file: PathBuf::from(c.database_file.unwrap_or(DATABASE_FILE.to_string())),
-        gen_tail_max_attamps: c
-            .gen_tail_max_attamps
-            .map(|v| v as usize)
-            .unwrap_or(GEN_TAIL_MAX_ATTAMPS),
-        default_tail_len: c
-            .default_tail_len
-            .map(|v| v as usize)
-            .unwrap_or(DEFAULT_TAIL_LEN),
-        min_expire_duration: c.min_expire_duration.unwrap_or(MIN_EXPIRE_AGE),
-        max_expire_duration: c.max_expire_duration.unwrap_or(MAX_EXPIRE_AGE),
-        max_file_size: c.max_file_size.map(|v| v as usize).unwrap_or(MAX_FILE_SIZE),
-        cleanup_urls_duration: c
-            .cleanup_urls_duration
-            .map(|v| v as u64)
-            .unwrap_or(CLEANUP_URLS_DURATION),
-        cleanup_files_duration: c
-            .cleanup_files_duration
-            .map(|v| v as u64)
-            .unwrap_or(CLEANUP_FILES_DURATION),
+        base_url,
+        upload_file_dir,
+        database_file,
+        database_url: c.database_url.unwrap_or_default(),
+        sqlite_pool_size: sqlite_pool_size as usize,
+        sqlite_wal: c.sqlite_wal.unwrap_or(true),
+        sqlite_synchronous,
+        sqlite_busy_timeout: sqlite_busy_timeout as u64,
+        gen_tail_max_attamps: gen_tail_max_attamps as usize,
+        default_tail_len: default_tail_len as usize,
+        min_expire_duration,
+        max_expire_duration,
+        max_file_size: max_file_size as usize,
+        max_batch_files: max_batch_files as usize,
+        max_batch_size: max_batch_size as usize,
+        max_total_storage: max_total_storage as u64,
+        eviction_policy,
+        cleanup_urls_duration: cleanup_urls_duration as u64,
+        cleanup_files_duration: cleanup_files_duration as u64,
+        cleanup_grace_period: cleanup_grace_period as u64,
+        scrub_duration: scrub_duration as u64,
+        verify_on_read: c.verify_on_read.unwrap_or(false),
+        shutdown_timeout: shutdown_timeout as u64,
+        storage_backend,
+        s3_endpoint: c.s3_endpoint.unwrap_or_default(),
+        s3_bucket: c.s3_bucket.unwrap_or_default(),
+        s3_region: c.s3_region.unwrap_or(S3_REGION.to_string()),
+        s3_access_key: c.s3_access_key.unwrap_or_default(),
+        s3_secret_key: c.s3_secret_key.unwrap_or_default(),
+        s3_prefix: c.s3_prefix.unwrap_or_default(),
+        compression_at_rest,
+        compression_mimetypes: c
+            .compression_mimetypes
+            .unwrap_or(COMPRESSION_MIMETYPES.map(|s| s.to_string()).to_vec()),
+        response_compression,
+        response_compression_min_size: response_compression_min_size as usize,
+        retention_rules,
     });
 }
 
-static CONFIG: OnceLock<Config> = OnceLock::new();
+static CONFIG: OnceLock<ArcSwap<Config>> = OnceLock::new();
+/// The config file and overrides given at startup, read again on reload.
+type Sources = (Option<PathBuf>, Vec<(String, toml::Value)>);
+static SOURCES: OnceLock<Sources> = OnceLock::new();
+/// Woken after every reload, so tasks sleeping on an interval pick up the new one.
+pub static CONFIG_RELOADED: Notify = Notify::const_new();
 
-pub fn init_config(path: Option<PathBuf>) -> Result<(), AppError> {
-    let config = match path {
-        Some(path) => read_config(&path)?,
-        None => Config::default(),
-    };
-    CONFIG.get_or_init(|| config);
+pub fn init_config(
+    path: Option<PathBuf>,
+    overrides: &[(String, toml::Value)],
+) -> Result<(), AppError> {
+    let config = read_config(path.as_deref(), overrides)?;
+    SOURCES.get_or_init(|| (path, overrides.to_vec()));
+    CONFIG.get_or_init(|| ArcSwap::from_pointee(config));
+    return Ok(());
+}
+
+/// Reads the config again from the sources it was loaded from at startup.
+/// Fields only read at startup keep their running values and are logged as
+/// needing a restart, an invalid config leaves the running one in place.
+pub fn reload_config() -> Result<(), AppError> {
+    let (path, overrides) = SOURCES.get().unwrap();
+    let mut new = read_config(path.as_deref(), overrides)?;
+    let old = conf();
+
+    let mut restart = Vec::new();
+    macro_rules! keep_running {
+        ($($field:ident),*) => {
+            $(
+                if new.$field != old.$field {
+                    restart.push(stringify!($field));
+                    new.$field = old.$field.clone();
+                }
+            )*
+        };
+    }
+    keep_running!(
+        listen_addr,
+        upload_file_dir,
+        database_file,
+        database_url,
+        sqlite_pool_size,
+        sqlite_wal,
+        sqlite_synchronous,
+        sqlite_busy_timeout,
+        storage_backend,
+        s3_endpoint,
+        s3_bucket,
+        s3_region,
+        s3_access_key,
+        s3_secret_key,
+        s3_prefix,
+        response_compression,
+        // a blob is encoded by whichever upload wrote it last while its row
+        // takes the encoding of whichever committed last, both have to agree
+        compression_at_rest,
+        compression_mimetypes
+    );
+    // the S3 client's response buffer was sized for the old limit
+    if old.storage_backend == StorageBackend::S3 && new.max_file_size > old.max_file_size {
+        restart.push("max_file_size");
+        new.max_file_size = old.max_file_size;
+    }
+    if !restart.is_empty() {
+        tracing::warn!(
+            "changes to {} only take effect after a restart",
+            restart.join(", ")
+        );
+    }
+
+    CONFIG.get().unwrap().store(Arc::new(new));
+    CONFIG_RELOADED.notify_waiters();
+    tracing::info!("config reloaded");
     return Ok(());
 }
 
-pub fn conf() -> &'static Config {
-    return CONFIG.get().unwrap();
+pub fn conf() -> Arc<Config> {
+    return CONFIG.get().unwrap().load_full();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_match_config_file_fields() {
+        let unknown = toml::Table::from_iter([("".to_string(), toml::Value::Boolean(true))]);
+        let e = toml::Value::Table(unknown)
+            .try_into::<ConfigFile>()
+            .unwrap_err()
+            .to_string();
+        // serde lists every field it expects
+        let (_, expected) = e.split_once("expected one of").unwrap();
+        let fields = expected
+            .split('`')
+            .skip(1)
+            .step_by(2)
+            .take_while(|field| !field.contains('\n'))
+            .collect::<Vec<_>>();
+        assert_eq!(fields, KEYS);
+    }
+
+    fn config_file(toml: &str) -> ConfigFile {
+        return toml::from_str(toml).unwrap();
+    }
+
+    #[test]
+    fn busy_timeout_numbers_are_milliseconds() {
+        assert_eq!(
+            config_file("sqlite_busy_timeout = 5000").sqlite_busy_timeout,
+            Some(5000)
+        );
+        assert_eq!(
+            config_file("sqlite_busy_timeout = \"1s 500ms\"").sqlite_busy_timeout,
+            Some(1500)
+        );
+    }
+
+    #[test]
+    fn duration_numbers_are_seconds() {
+        assert_eq!(
+            config_file("shutdown_timeout = 30").shutdown_timeout,
+            Some(30)
+        );
+        assert_eq!(
+            config_file("min_expire_duration = \"1h\"").min_expire_duration,
+            Some(3600)
+        );
+    }
+
+    #[test]
+    fn parse_value_takes_string_keys_verbatim() {
+        assert_eq!(
+            parse_value("s3_bucket", "1234"),
+            toml::Value::String("1234".to_string())
+        );
+        assert_eq!(
+            parse_value("listen_addr", "\"quoted\""),
+            toml::Value::String("\"quoted\"".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_value_reads_other_keys_as_toml() {
+        assert_eq!(
+            parse_value("max_file_size", "1024"),
+            toml::Value::Integer(1024)
+        );
+        assert_eq!(
+            parse_value("verify_on_read", "true"),
+            toml::Value::Boolean(true)
+        );
+        assert_eq!(
+            parse_value("response_compression", "[\"gzip\", \"zstd\"]"),
+            toml::Value::Array(vec![
+                toml::Value::String("gzip".to_string()),
+                toml::Value::String("zstd".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_value_falls_back_to_a_string() {
+        assert_eq!(
+            parse_value("max_file_size", "1MiB"),
+            toml::Value::String("1MiB".to_string())
+        );
+        assert_eq!(
+            parse_value("min_expire_duration", "7 days"),
+            toml::Value::String("7 days".to_string())
+        );
+    }
 }
@@ -1,156 +1,209 @@
-use crate::{error::AppError, conf};
-
-use deadpool_sqlite::Pool;
-use deadpool_sqlite::rusqlite::OptionalExtension;
-use rand::distr::{Alphabetic, SampleString};
-
-pub async fn init_db(db_pool: &Pool) -> Result<(), AppError> {
-    let db_conn = db_pool.get().await?;
-    return db_conn
-        .interact(|conn| {
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS files(
-                    file_sha256sum TEXT PRIMARY KEY,
-                    ref_count INTEGER
-                )",
-                (),
-            )?;
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS urls(
-                    tail TEXT PRIMARY KEY,
-                    file_sha256sum TEXT,
-                    mimetype TEXT,
-                    expires_at INTEGER
-                )",
-                (),
-            )?;
-            conn.execute(
-                "CREATE INDEX IF NOT EXISTS index_expires_at ON urls(expires_at)",
-                (),
-            )?;
-            return Ok(());
-        })
-        .await?;
+mod postgres;
+mod sqlite;
+
+use std::sync::Arc;
+
+use crate::{conf, error::AppError};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+pub use postgres::PostgresRepository;
+pub use sqlite::SqliteRepository;
+
+#[derive(Clone)]
+pub struct NewUrl {
+    pub file_sha256sum: String,
+    pub mimetype: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    /// only used for collections
+    pub filename: String,
+    pub encoding: String,
+    pub size: i64,
+    pub stored_size: i64,
 }
 
-pub async fn add_url(
-    db_pool: &Pool,
-    tail_len: usize,
-    file_sha256sum_: &str,
-    mimetype_: &str,
-    expires_at: i64,
-) -> Result<String, AppError> {
-    let file_sha256sum = file_sha256sum_.to_string();
-    let mimetype = mimetype_.to_string();
-    let db_conn = db_pool.get().await?;
-    return db_conn
-        .interact(move |conn| {
-            let tx = conn.transaction()?;
-
-            let mut tail = None;
-            let max_attamps = conf().gen_tail_max_attamps;
-            for _ in 0..max_attamps {
-                let try_tail = Alphabetic.sample_string(&mut rand::rng(), tail_len);
-                let exist = tx.query_row(
-                    "SELECT EXISTS(SELECT 1 FROM urls WHERE tail = ?1)",
-                    (&try_tail,),
-                    |row| row.get::<_, bool>(0),
-                )?;
-
-                if !exist {
-                    tail = Some(try_tail);
-                    break;
-                }
-            }
-
-            let tail = tail.ok_or(AppError::TailDrained)?;
-
-            tx.execute(
-                "INSERT INTO files VALUES (?1, 1) 
-                ON CONFLICT DO UPDATE SET ref_count = ref_count + 1",
-                (&file_sha256sum,),
-            )?;
-
-            tx.execute(
-                "INSERT INTO urls VALUES (?1, ?2, ?3, ?4)",
-                (&tail, &file_sha256sum, &mimetype, expires_at),
-            )?;
-
-            tx.commit()?;
-
-            return Ok(tail);
-        })
-        .await?;
+/// The file a url points at.
+pub struct UrlFile {
+    pub file_sha256sum: String,
+    pub mimetype: String,
+    pub expires_at: i64,
+    pub encoding: String,
+    pub size: Option<u64>,
+    pub created_at: Option<i64>,
+    pub last_accessed_at: Option<i64>,
+    pub access_count: i64,
 }
 
-pub async fn cleanup_expired_urls(db_pool: &Pool, now: i64) -> Result<(), AppError> {
-    let db_conn = db_pool.get().await?;
-    return db_conn
-        .interact(move |conn| {
-            let tx = conn.transaction()?;
-            tx.execute(
-                "WITH expired_count AS (
-                    SELECT file_sha256sum, COUNT(*) AS decr
-                    FROM urls
-                    WHERE expires_at <= ?1
-                    GROUP BY file_sha256sum
-                )
-                UPDATE files
-                SET ref_count = ref_count - expired_count.decr
-                FROM expired_count
-                WHERE files.file_sha256sum = expired_count.file_sha256sum",
-                (now,),
-            )?;
-            tx.execute("DELETE FROM urls WHERE expires_at <= ?1", (now,))?;
-            tx.execute("DELETE FROM files WHERE ref_count = 0", ())?;
-            tx.commit()?;
-            return Ok(());
-        })
-        .await?;
+/// A url with everything needed to recreate it in another instance.
+#[derive(Serialize, Deserialize)]
+pub struct UrlRecord {
+    pub tail: String,
+    pub file_sha256sum: String,
+    pub mimetype: String,
+    pub created_at: Option<i64>,
+    pub expires_at: i64,
+    pub last_accessed_at: Option<i64>,
+    pub access_count: i64,
+    pub encoding: String,
+    pub size: Option<i64>,
+    pub stored_size: Option<i64>,
+    /// the collection the url belongs to, if any
+    pub collection: Option<String>,
+    /// only set together with `collection`
+    pub filename: Option<String>,
 }
 
-pub async fn cleanup_unreachable_files(db_pool: &Pool) -> Result<(), AppError> {
-    let db_conn = db_pool.get().await?;
-    return db_conn
-        .interact(|conn| {
-            let upload_dir = std::fs::read_dir(&conf().upload_file_dir)?;
-            for entry in upload_dir {
-                let path = entry?.path();
-                if !path.is_file() {
-                    continue;
-                }
-
-                let exist = conn.query_row(
-                    "SELECT EXISTS(SELECT 1 FROM files WHERE file_sha256sum = ?1)",
-                    (path.file_name().unwrap().to_string_lossy(),),
-                    |row| row.get::<_, bool>(0),
-                )?;
-
-                if !exist {
-                    std::fs::remove_file(path)?;
-                }
-            }
-            return Ok(());
-        })
-        .await?;
+/// Counts over the whole database.
+pub struct DbStats {
+    pub urls: i64,
+    pub collections: i64,
+    pub files: i64,
+    /// bytes of content of all blobs
+    pub size: i64,
+    /// bytes all blobs take in storage
+    pub stored_size: i64,
 }
 
-pub async fn get_file_by_url(
-    db_pool: &Pool,
-    tail: &str,
-) -> Result<Option<(String, String)>, AppError> {
-    let db_conn = db_pool.get().await?;
-    let db_param = (tail.to_string(),);
-    return db_conn
-        .interact(move |conn| {
-            return conn
-                .query_row(
-                    "SELECT file_sha256sum, mimetype FROM urls WHERE tail = ?1",
-                    db_param,
-                    |row| Ok((row.get(0)?, row.get(1)?)),
-                )
-                .optional()
-                .map_err(|e| AppError::Sqlite(e));
-        })
-        .await?;
+const STATS: &str = "
+    SELECT
+        (SELECT COUNT(*) FROM urls),
+        (SELECT COUNT(DISTINCT collection) FROM collection_files),
+        (SELECT COUNT(*) FROM files),
+        (SELECT CAST(COALESCE(SUM(COALESCE(size, 0)), 0) AS BIGINT) FROM files),
+        (SELECT CAST(COALESCE(SUM(COALESCE(stored_size, size, 0)), 0) AS BIGINT) FROM files)";
+
+const TOTAL_STORED_SIZE: &str =
+    "SELECT CAST(COALESCE(SUM(COALESCE(stored_size, size, 0)), 0) AS BIGINT) FROM files";
+
+/// Valid on both backends, `ref_count` may be NULL in old SQLite databases.
+const CHECK_REF_COUNTS: &str = "
+    SELECT file_sha256sum, ref_count, actual FROM (
+        SELECT file_sha256sum, ref_count,
+            (SELECT COUNT(*) FROM urls WHERE urls.file_sha256sum = files.file_sha256sum) AS actual
+        FROM files
+    ) AS counted
+    WHERE ref_count IS NULL OR ref_count != actual
+    UNION ALL
+    SELECT file_sha256sum, NULL, COUNT(*) FROM urls
+    WHERE NOT EXISTS(SELECT 1 FROM files WHERE files.file_sha256sum = urls.file_sha256sum)
+    GROUP BY file_sha256sum";
+
+const FIX_REF_COUNTS: [&str; 3] = [
+    "INSERT INTO files(file_sha256sum, ref_count)
+    SELECT DISTINCT file_sha256sum, 0 FROM urls
+    WHERE NOT EXISTS(SELECT 1 FROM files WHERE files.file_sha256sum = urls.file_sha256sum)",
+    "UPDATE files SET ref_count =
+        (SELECT COUNT(*) FROM urls WHERE urls.file_sha256sum = files.file_sha256sum)",
+    "DELETE FROM files WHERE ref_count = 0",
+];
+
+const URL_FILE_COLUMNS: &str = "urls.file_sha256sum, urls.mimetype, urls.expires_at,
+    files.encoding, files.size, urls.created_at, urls.last_accessed_at, urls.access_count";
+
+/// Every query webpaste makes, `files` counts the urls referring to each blob
+/// and `urls` maps tails to blobs.
+#[async_trait]
+pub trait Repository: Send + Sync {
+    /// Brings the schema up to date, refuses databases migrated by a newer version.
+    async fn migrate(&self) -> Result<(), AppError>;
+
+    /// Adds a url, evicting others if its blob doesn't fit under
+    /// `max_total_storage` otherwise.
+    async fn add_url(&self, tail_len: usize, url: NewUrl) -> Result<String, AppError>;
+
+    /// Adds one url per file plus a collection tail grouping all of them, returns
+    /// the collection tail and the file tails in upload order. Evicts like
+    /// `add_url`.
+    async fn add_collection(
+        &self,
+        tail_len: usize,
+        urls: Vec<NewUrl>,
+    ) -> Result<(String, Vec<String>), AppError>;
+
+    async fn cleanup_expired_urls(&self, now: i64) -> Result<(), AppError>;
+
+    /// Returns the blob names in `names` that no `files` row refers to.
+    async fn filter_unreachable_files(&self, names: Vec<String>) -> Result<Vec<String>, AppError>;
+
+    /// Bytes taken by all blobs, rows of older versions without `stored_size`
+    /// hold uncompressed blobs.
+    async fn total_stored_size(&self) -> Result<u64, AppError>;
+
+    async fn record_access(&self, tail: &str, now: i64) -> Result<(), AppError>;
+
+    /// Names of blobs whose size was never recorded, from rows of older versions.
+    async fn list_files_without_size(&self) -> Result<Vec<String>, AppError>;
+
+    /// Fills in missing sizes from the size of the stored blob, blobs without a
+    /// content size predate compression so both are the same.
+    async fn set_file_sizes(&self, sizes: Vec<(String, u64)>) -> Result<(), AppError>;
+
+    /// Returns the name and encoding of every blob in `files`.
+    async fn list_files(&self) -> Result<Vec<(String, String)>, AppError>;
+
+    /// Returns the encoding of a blob, or `None` without a `files` row.
+    async fn get_file_encoding(&self, name: &str) -> Result<Option<String>, AppError>;
+
+    /// Returns up to `limit` names from `files` sorting after `after`, in
+    /// order, so every blob can be visited without one long query.
+    async fn list_file_names(&self, after: &str, limit: usize) -> Result<Vec<String>, AppError>;
+
+    /// Removes the `files` rows in `names` together with every url pointing at
+    /// them, returns how many urls were removed.
+    async fn remove_files(&self, names: Vec<String>) -> Result<usize, AppError>;
+
+    /// Returns every blob whose `ref_count` differs from the number of urls
+    /// referring to it, as name, recorded count (`None` without a `files` row)
+    /// and actual count.
+    async fn check_ref_counts(&self) -> Result<Vec<(String, Option<i64>, i64)>, AppError>;
+
+    /// Sets every `ref_count` to the number of urls referring to the blob,
+    /// adding missing `files` rows and dropping unreferenced ones.
+    async fn fix_ref_counts(&self) -> Result<(), AppError>;
+
+    /// Deletes a url, or every url of a collection if `tail` names one, returns
+    /// how many urls were removed. The blobs are left to the file cleanup.
+    async fn delete_url(&self, tail: &str) -> Result<usize, AppError>;
+
+    async fn stats(&self) -> Result<DbStats, AppError>;
+
+    /// Returns up to `limit` urls with a tail sorting after `after`, in order.
+    async fn list_urls(&self, after: &str, limit: usize) -> Result<Vec<UrlRecord>, AppError>;
+
+    /// Returns the records `import_urls` would add right now.
+    async fn filter_importable(&self, records: Vec<UrlRecord>) -> Result<Vec<UrlRecord>, AppError>;
+
+    /// Adds urls under their recorded tails, returns how many were added. Urls
+    /// whose tail is already taken, or whose collection is named like an
+    /// existing url, are skipped. Blobs that already have a `files` row keep
+    /// their recorded encoding and sizes.
+    async fn import_urls(&self, records: Vec<UrlRecord>) -> Result<usize, AppError>;
+
+    /// Closes the pool, connections in use are closed once returned.
+    fn close(&self);
+
+    async fn get_file_by_url(&self, tail: &str) -> Result<Option<UrlFile>, AppError>;
+
+    /// Returns the filename and file of every url in a collection, in upload order.
+    async fn get_collection_files(
+        &self,
+        collection: &str,
+    ) -> Result<Vec<(String, UrlFile)>, AppError>;
+}
+
+/// Shared by the handlers and the background tasks.
+pub type Db = Arc<dyn Repository>;
+
+/// Connects to Postgres if `database_url` is set and to `database_file`
+/// otherwise, then migrates the schema.
+pub async fn init_db() -> Result<Db, AppError> {
+    let c = conf();
+    let db: Db = match c.database_url.is_empty() {
+        true => Arc::new(SqliteRepository::new(&c.database_file)?),
+        false => Arc::new(PostgresRepository::new(&c.database_url)?),
+    };
+    db.migrate().await?;
+    return Ok(db);
 }
@@ -0,0 +1,690 @@
+use std::collections::HashSet;
+
+use crate::conf;
+use crate::db::{
+    CHECK_REF_COUNTS, DbStats, FIX_REF_COUNTS, NewUrl, Repository, STATS, TOTAL_STORED_SIZE,
+    URL_FILE_COLUMNS, UrlFile, UrlRecord,
+};
+use crate::error::AppError;
+use crate::quota::{EvictionPlan, eviction_query, space_needed};
+
+use async_trait::async_trait;
+use deadpool_postgres::tokio_postgres::{Row, Transaction};
+use deadpool_postgres::{Config, Pool, Runtime};
+use futures_util::TryStreamExt;
+use native_tls::TlsConnector;
+use postgres_native_tls::MakeTlsConnector;
+use rand::distr::{Alphabetic, SampleString};
+
+/// Key of the advisory lock held while migrating, so replicas starting at
+/// the same time migrate one after another.
+const MIGRATION_LOCK: i64 = 0x7765_6270_6173_7465;
+/// Key of the advisory lock held while an upload checks and makes room under
+/// `max_total_storage`.
+const QUOTA_LOCK: i64 = MIGRATION_LOCK + 1;
+/// Key of the advisory lock held by the url cleanup, one replica at a time.
+const CLEANUP_LOCK: i64 = MIGRATION_LOCK + 2;
+/// First key of the advisory locks taken on candidate tails, the second is
+/// the hash of the tail.
+const TAIL_LOCK_CLASS: i32 = 0x7770;
+
+const BASELINE: &str = "
+    CREATE TABLE files(
+        file_sha256sum TEXT PRIMARY KEY,
+        ref_count BIGINT NOT NULL,
+        encoding TEXT NOT NULL DEFAULT '',
+        size BIGINT,
+        stored_size BIGINT
+    );
+    CREATE TABLE urls(
+        tail TEXT PRIMARY KEY,
+        file_sha256sum TEXT NOT NULL,
+        mimetype TEXT NOT NULL,
+        created_at BIGINT,
+        expires_at BIGINT NOT NULL,
+        last_accessed_at BIGINT,
+        access_count BIGINT NOT NULL DEFAULT 0
+    );
+    CREATE INDEX index_expires_at ON urls(expires_at);
+    CREATE TABLE collection_files(
+        id BIGSERIAL,
+        collection TEXT,
+        tail TEXT,
+        filename TEXT NOT NULL,
+        PRIMARY KEY (collection, tail)
+    );
+    CREATE INDEX index_collection_files_tail ON collection_files(tail);
+";
+
+/// Lets the LRU eviction read urls in order instead of sorting all of them.
+const LRU_INDEX: &str =
+    "CREATE INDEX index_urls_lru ON urls((COALESCE(last_accessed_at, 0)), expires_at);";
+
+/// Step `i` brings the schema from version `i` to `i + 1`, the same rules as
+/// the SQLite migrations apply.
+const MIGRATIONS: &[&str] = &[BASELINE, LRU_INDEX];
+
+async fn gen_tail(tx: &Transaction<'_>, tail_len: usize) -> Result<String, AppError> {
+    let max_attamps = conf().gen_tail_max_attamps;
+    for _ in 0..max_attamps {
+        let try_tail = Alphabetic.sample_string(&mut rand::rng(), tail_len);
+        // a tail locked by another replica is about to be taken, the lock is
+        // taken before the check so the check sees everything committed before it
+        let locked = tx
+            .query_one(
+                "SELECT pg_try_advisory_xact_lock($1, hashtext($2))",
+                &[&TAIL_LOCK_CLASS, &try_tail],
+            )
+            .await?
+            .try_get::<_, bool>(0)?;
+        if !locked {
+            continue;
+        }
+        let exist = tx
+            .query_one(
+                "SELECT EXISTS(SELECT 1 FROM urls WHERE tail = $1)
+                    OR EXISTS(SELECT 1 FROM collection_files WHERE collection = $1)",
+                &[&try_tail],
+            )
+            .await?
+            .try_get::<_, bool>(0)?;
+
+        if !exist {
+            return Ok(try_tail);
+        }
+    }
+    return Err(AppError::TailDrained);
+}
+
+async fn insert_url(tx: &Transaction<'_>, tail: &str, url: &NewUrl) -> Result<(), AppError> {
+    // the blob is rewritten on every upload, so the row follows its encoding
+    tx.execute(
+        "INSERT INTO files(file_sha256sum, ref_count, encoding, size, stored_size)
+        VALUES ($1, 1, $2, $3, $4)
+        ON CONFLICT (file_sha256sum) DO UPDATE SET
+            ref_count = files.ref_count + 1, encoding = excluded.encoding,
+            size = excluded.size, stored_size = excluded.stored_size",
+        &[
+            &url.file_sha256sum,
+            &url.encoding,
+            &url.size,
+            &url.stored_size,
+        ],
+    )
+    .await?;
+
+    tx.execute(
+        "INSERT INTO urls(tail, file_sha256sum, mimetype, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5)",
+        &[
+            &tail,
+            &url.file_sha256sum,
+            &url.mimetype,
+            &url.created_at,
+            &url.expires_at,
+        ],
+    )
+    .await?;
+    return Ok(());
+}
+
+/// Removes a url and decrements the ref count of its blob.
+async fn remove_url(tx: &Transaction<'_>, tail: &str) -> Result<bool, AppError> {
+    let row = tx
+        .query_opt(
+            "DELETE FROM urls WHERE tail = $1 RETURNING file_sha256sum",
+            &[&tail],
+        )
+        .await?;
+    let Some(row) = row else {
+        return Ok(false);
+    };
+    let file_sha256sum = row.try_get::<_, String>(0)?;
+    tx.execute("DELETE FROM collection_files WHERE tail = $1", &[&tail])
+        .await?;
+    tx.execute(
+        "UPDATE files SET ref_count = ref_count - 1 WHERE file_sha256sum = $1",
+        &[&file_sha256sum],
+    )
+    .await?;
+    tx.execute(
+        "DELETE FROM files WHERE file_sha256sum = $1 AND ref_count <= 0",
+        &[&file_sha256sum],
+    )
+    .await?;
+    return Ok(true);
+}
+
+/// Evicts urls until the blobs of `urls` fit under `max_total_storage`. Runs
+/// in the transaction adding them and holds `QUOTA_LOCK`, so concurrent
+/// uploads, of any replica, can't all fit.
+async fn make_room(tx: &Transaction<'_>, urls: &[NewUrl]) -> Result<(), AppError> {
+    if conf().max_total_storage == 0 {
+        return Ok(());
+    }
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&QUOTA_LOCK])
+        .await?;
+    let mut names = HashSet::new();
+    let mut incoming = 0;
+    for url in urls {
+        if !names.insert(url.file_sha256sum.clone()) {
+            continue;
+        }
+        let exist = tx
+            .query_one(
+                "SELECT EXISTS(SELECT 1 FROM files WHERE file_sha256sum = $1)",
+                &[&url.file_sha256sum],
+            )
+            .await?
+            .try_get::<_, bool>(0)?;
+        if !exist {
+            incoming += url.stored_size as u64;
+        }
+    }
+    let total = tx
+        .query_one(TOTAL_STORED_SIZE, &[])
+        .await?
+        .try_get::<_, i64>(0)?;
+    let Some((needed, order_by)) = space_needed(total as u64, incoming)? else {
+        return Ok(());
+    };
+
+    let mut plan = EvictionPlan::new(needed, names);
+    let rows = tx
+        .query_raw(&eviction_query(order_by), Vec::<String>::new())
+        .await?;
+    futures_util::pin_mut!(rows);
+    while let Some(row) = rows.try_next().await? {
+        let size = row.try_get::<_, i64>(3)? as u64;
+        if plan.pick(row.try_get(0)?, row.try_get(1)?, row.try_get(2)?, size) {
+            break;
+        }
+    }
+    if !plan.is_enough() {
+        return Err(AppError::StorageFull);
+    }
+    for tail in &plan.tails {
+        remove_url(tx, tail).await?;
+    }
+    tracing::info!(
+        "evicted {} urls to free {} bytes",
+        plan.tails.len(),
+        plan.freed
+    );
+    return Ok(());
+}
+
+/// Reads the row selected with `URL_FILE_COLUMNS` starting at `offset`.
+fn url_file_from_row(row: &Row, offset: usize) -> Result<UrlFile, AppError> {
+    return Ok(UrlFile {
+        file_sha256sum: row.try_get(offset)?,
+        mimetype: row.try_get(offset + 1)?,
+        expires_at: row.try_get(offset + 2)?,
+        encoding: row.try_get(offset + 3)?,
+        size: row
+            .try_get::<_, Option<i64>>(offset + 4)?
+            .map(|size| size as u64),
+        created_at: row.try_get(offset + 5)?,
+        last_accessed_at: row.try_get(offset + 6)?,
+        access_count: row.try_get(offset + 7)?,
+    });
+}
+
+/// A shared PostgreSQL database, lets several replicas serve the same urls.
+pub struct PostgresRepository {
+    pool: Pool,
+}
+
+impl PostgresRepository {
+    pub fn new(url: &str) -> Result<Self, AppError> {
+        let config = Config {
+            url: Some(url.to_string()),
+            ..Default::default()
+        };
+        // certificates are checked against the system roots, `sslmode` in
+        // the url decides whether the connection may fall back to plain text
+        let tls = TlsConnector::new().map_err(|e| AppError::Database(e.to_string()))?;
+        let pool = config
+            .create_pool(Some(Runtime::Tokio1), MakeTlsConnector::new(tls))
+            .map_err(|e| AppError::Database(e.to_string()))?;
+        return Ok(Self { pool });
+    }
+}
+
+#[async_trait]
+impl Repository for PostgresRepository {
+    async fn migrate(&self) -> Result<(), AppError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+            .await?;
+        let versioned = tx
+            .query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[])
+            .await?
+            .try_get::<_, bool>(0)?;
+        if !versioned {
+            tx.batch_execute("CREATE TABLE schema_version(version BIGINT NOT NULL)")
+                .await?;
+        }
+        let version = match tx
+            .query_opt("SELECT version FROM schema_version", &[])
+            .await?
+        {
+            Some(row) => row.try_get::<_, i64>(0)? as usize,
+            None => 0,
+        };
+        if version > MIGRATIONS.len() {
+            return Err(AppError::SchemaTooNew(version, MIGRATIONS.len()));
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            tracing::info!("migrating database schema to version {}", i + 1);
+            tx.batch_execute(migration).await?;
+        }
+        tx.execute("DELETE FROM schema_version", &[]).await?;
+        tx.execute(
+            "INSERT INTO schema_version VALUES ($1)",
+            &[&(MIGRATIONS.len() as i64)],
+        )
+        .await?;
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    async fn add_url(&self, tail_len: usize, url: NewUrl) -> Result<String, AppError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        make_room(&tx, std::slice::from_ref(&url)).await?;
+        let tail = gen_tail(&tx, tail_len).await?;
+        insert_url(&tx, &tail, &url).await?;
+        tx.commit().await?;
+        return Ok(tail);
+    }
+
+    async fn add_collection(
+        &self,
+        tail_len: usize,
+        urls: Vec<NewUrl>,
+    ) -> Result<(String, Vec<String>), AppError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        make_room(&tx, &urls).await?;
+        let collection = gen_tail(&tx, tail_len).await?;
+        let mut tails = Vec::with_capacity(urls.len());
+        for url in urls {
+            let tail = gen_tail(&tx, tail_len).await?;
+            insert_url(&tx, &tail, &url).await?;
+            tx.execute(
+                "INSERT INTO collection_files(collection, tail, filename) VALUES ($1, $2, $3)",
+                &[&collection, &tail, &url.filename],
+            )
+            .await?;
+            tails.push(tail);
+        }
+        tx.commit().await?;
+        return Ok((collection, tails));
+    }
+
+    async fn cleanup_expired_urls(&self, now: i64) -> Result<(), AppError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&CLEANUP_LOCK])
+            .await?;
+        // ref counts drop by the urls this statement actually deleted, a url
+        // removed concurrently isn't returned and so isn't counted twice
+        tx.execute(
+            "WITH expired AS (
+                DELETE FROM urls WHERE expires_at <= $1 RETURNING tail, file_sha256sum
+            ), expired_collection_files AS (
+                DELETE FROM collection_files WHERE tail IN (SELECT tail FROM expired)
+            ), expired_count AS (
+                SELECT file_sha256sum, COUNT(*) AS decr FROM expired GROUP BY file_sha256sum
+            )
+            UPDATE files
+            SET ref_count = ref_count - expired_count.decr
+            FROM expired_count
+            WHERE files.file_sha256sum = expired_count.file_sha256sum",
+            &[&now],
+        )
+        .await?;
+        tx.execute("DELETE FROM files WHERE ref_count <= 0", &[])
+            .await?;
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    async fn filter_unreachable_files(&self, names: Vec<String>) -> Result<Vec<String>, AppError> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT name FROM unnest($1::TEXT[]) AS name
+                WHERE NOT EXISTS(SELECT 1 FROM files WHERE file_sha256sum = name)",
+                &[&names],
+            )
+            .await?;
+        return Ok(rows
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<_, _>>()?);
+    }
+
+    async fn total_stored_size(&self) -> Result<u64, AppError> {
+        let client = self.pool.get().await?;
+        let total = client
+            .query_one(TOTAL_STORED_SIZE, &[])
+            .await?
+            .try_get::<_, i64>(0)?;
+        return Ok(total as u64);
+    }
+
+    async fn record_access(&self, tail: &str, now: i64) -> Result<(), AppError> {
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "UPDATE urls SET last_accessed_at = $2, access_count = access_count + 1
+                WHERE tail = $1",
+                &[&tail, &now],
+            )
+            .await?;
+        return Ok(());
+    }
+
+    async fn list_files_without_size(&self) -> Result<Vec<String>, AppError> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT file_sha256sum FROM files WHERE size IS NULL OR stored_size IS NULL",
+                &[],
+            )
+            .await?;
+        return Ok(rows
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<_, _>>()?);
+    }
+
+    async fn set_file_sizes(&self, sizes: Vec<(String, u64)>) -> Result<(), AppError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        for (name, size) in sizes {
+            tx.execute(
+                "UPDATE files SET size = COALESCE(size, $2), stored_size = $2
+                WHERE file_sha256sum = $1",
+                &[&name, &(size as i64)],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    async fn list_files(&self) -> Result<Vec<(String, String)>, AppError> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query("SELECT file_sha256sum, encoding FROM files", &[])
+            .await?;
+        return rows
+            .iter()
+            .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+            .collect();
+    }
+
+    async fn get_file_encoding(&self, name: &str) -> Result<Option<String>, AppError> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                "SELECT encoding FROM files WHERE file_sha256sum = $1",
+                &[&name],
+            )
+            .await?;
+        return Ok(row.map(|row| row.try_get(0)).transpose()?);
+    }
+
+    async fn list_file_names(&self, after: &str, limit: usize) -> Result<Vec<String>, AppError> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT file_sha256sum FROM files WHERE file_sha256sum > $1
+                ORDER BY file_sha256sum LIMIT $2",
+                &[&after, &(limit as i64)],
+            )
+            .await?;
+        return Ok(rows
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<_, _>>()?);
+    }
+
+    async fn remove_files(&self, names: Vec<String>) -> Result<usize, AppError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let mut removed = 0;
+        for name in names {
+            tx.execute(
+                "DELETE FROM collection_files
+                WHERE tail IN (SELECT tail FROM urls WHERE file_sha256sum = $1)",
+                &[&name],
+            )
+            .await?;
+            removed += tx
+                .execute("DELETE FROM urls WHERE file_sha256sum = $1", &[&name])
+                .await? as usize;
+            tx.execute("DELETE FROM files WHERE file_sha256sum = $1", &[&name])
+                .await?;
+        }
+        tx.commit().await?;
+        return Ok(removed);
+    }
+
+    async fn check_ref_counts(&self) -> Result<Vec<(String, Option<i64>, i64)>, AppError> {
+        let client = self.pool.get().await?;
+        let rows = client.query(CHECK_REF_COUNTS, &[]).await?;
+        return rows
+            .iter()
+            .map(|row| Ok((row.try_get(0)?, row.try_get(1)?, row.try_get(2)?)))
+            .collect();
+    }
+
+    async fn fix_ref_counts(&self) -> Result<(), AppError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        for sql in FIX_REF_COUNTS {
+            tx.execute(sql, &[]).await?;
+        }
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    async fn delete_url(&self, tail: &str) -> Result<usize, AppError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let mut removed = 0;
+        if remove_url(&tx, tail).await? {
+            removed += 1;
+        } else {
+            let rows = tx
+                .query(
+                    "SELECT tail FROM collection_files WHERE collection = $1",
+                    &[&tail],
+                )
+                .await?;
+            for row in rows {
+                if remove_url(&tx, row.try_get(0)?).await? {
+                    removed += 1;
+                }
+            }
+        }
+        tx.commit().await?;
+        return Ok(removed);
+    }
+
+    async fn stats(&self) -> Result<DbStats, AppError> {
+        let client = self.pool.get().await?;
+        let row = client.query_one(STATS, &[]).await?;
+        return Ok(DbStats {
+            urls: row.try_get(0)?,
+            collections: row.try_get(1)?,
+            files: row.try_get(2)?,
+            size: row.try_get(3)?,
+            stored_size: row.try_get(4)?,
+        });
+    }
+
+    async fn list_urls(&self, after: &str, limit: usize) -> Result<Vec<UrlRecord>, AppError> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT urls.tail, urls.file_sha256sum, urls.mimetype, urls.created_at,
+                    urls.expires_at, urls.last_accessed_at, urls.access_count,
+                    COALESCE(files.encoding, ''), files.size, files.stored_size,
+                    collection_files.collection, collection_files.filename
+                FROM urls
+                LEFT JOIN files ON files.file_sha256sum = urls.file_sha256sum
+                LEFT JOIN collection_files ON collection_files.tail = urls.tail
+                WHERE urls.tail > $1
+                ORDER BY urls.tail LIMIT $2",
+                &[&after, &(limit as i64)],
+            )
+            .await?;
+        return rows
+            .iter()
+            .map(|row| {
+                Ok(UrlRecord {
+                    tail: row.try_get(0)?,
+                    file_sha256sum: row.try_get(1)?,
+                    mimetype: row.try_get(2)?,
+                    created_at: row.try_get(3)?,
+                    expires_at: row.try_get(4)?,
+                    last_accessed_at: row.try_get(5)?,
+                    access_count: row.try_get(6)?,
+                    encoding: row.try_get(7)?,
+                    size: row.try_get(8)?,
+                    stored_size: row.try_get(9)?,
+                    collection: row.try_get(10)?,
+                    filename: row.try_get(11)?,
+                })
+            })
+            .collect();
+    }
+
+    async fn filter_importable(&self, records: Vec<UrlRecord>) -> Result<Vec<UrlRecord>, AppError> {
+        let client = self.pool.get().await?;
+        let mut importable = Vec::new();
+        for r in records {
+            let taken = client
+                .query_one(
+                    "SELECT EXISTS(SELECT 1 FROM urls WHERE tail = $1)
+                        OR EXISTS(SELECT 1 FROM collection_files WHERE collection = $1)
+                        OR EXISTS(SELECT 1 FROM urls WHERE tail = $2)",
+                    &[&r.tail, &r.collection],
+                )
+                .await?
+                .try_get::<_, bool>(0)?;
+            if !taken {
+                importable.push(r);
+            }
+        }
+        return Ok(importable);
+    }
+
+    async fn import_urls(&self, records: Vec<UrlRecord>) -> Result<usize, AppError> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let mut imported = 0;
+        for r in records {
+            let taken = tx
+                .query_one(
+                    "SELECT EXISTS(SELECT 1 FROM urls WHERE tail = $1)
+                        OR EXISTS(SELECT 1 FROM collection_files WHERE collection = $1)
+                        OR EXISTS(SELECT 1 FROM urls WHERE tail = $2)",
+                    &[&r.tail, &r.collection],
+                )
+                .await?
+                .try_get::<_, bool>(0)?;
+            if taken {
+                continue;
+            }
+
+            tx.execute(
+                "INSERT INTO files(file_sha256sum, ref_count, encoding, size, stored_size)
+                VALUES ($1, 1, $2, $3, $4)
+                ON CONFLICT (file_sha256sum) DO UPDATE SET ref_count = files.ref_count + 1",
+                &[&r.file_sha256sum, &r.encoding, &r.size, &r.stored_size],
+            )
+            .await?;
+            tx.execute(
+                "INSERT INTO urls(tail, file_sha256sum, mimetype, created_at, expires_at,
+                    last_accessed_at, access_count)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &r.tail,
+                    &r.file_sha256sum,
+                    &r.mimetype,
+                    &r.created_at,
+                    &r.expires_at,
+                    &r.last_accessed_at,
+                    &r.access_count,
+                ],
+            )
+            .await?;
+            if let Some(collection) = &r.collection {
+                tx.execute(
+                    "INSERT INTO collection_files(collection, tail, filename)
+                    VALUES ($1, $2, $3)",
+                    &[
+                        collection,
+                        &r.tail,
+                        &r.filename.as_deref().unwrap_or_default(),
+                    ],
+                )
+                .await?;
+            }
+            imported += 1;
+        }
+        tx.commit().await?;
+        return Ok(imported);
+    }
+
+    fn close(&self) {
+        self.pool.close();
+    }
+
+    async fn get_file_by_url(&self, tail: &str) -> Result<Option<UrlFile>, AppError> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM urls JOIN files USING (file_sha256sum) WHERE tail = $1",
+                    URL_FILE_COLUMNS
+                ),
+                &[&tail],
+            )
+            .await?;
+        return row.map(|row| url_file_from_row(&row, 0)).transpose();
+    }
+
+    async fn get_collection_files(
+        &self,
+        collection: &str,
+    ) -> Result<Vec<(String, UrlFile)>, AppError> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT collection_files.filename, {}
+                    FROM collection_files
+                    JOIN urls ON urls.tail = collection_files.tail
+                    JOIN files USING (file_sha256sum)
+                    WHERE collection_files.collection = $1
+                    ORDER BY collection_files.id",
+                    URL_FILE_COLUMNS
+                ),
+                &[&collection],
+            )
+            .await?;
+        return rows
+            .iter()
+            .map(|row| Ok((row.try_get(0)?, url_file_from_row(row, 1)?)))
+            .collect();
+    }
+}
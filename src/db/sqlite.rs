@@ -0,0 +1,772 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::conf;
+use crate::db::{
+    CHECK_REF_COUNTS, DbStats, FIX_REF_COUNTS, NewUrl, Repository, STATS, TOTAL_STORED_SIZE,
+    URL_FILE_COLUMNS, UrlFile, UrlRecord,
+};
+use crate::error::AppError;
+use crate::quota::{EvictionPlan, eviction_query, space_needed};
+
+use async_trait::async_trait;
+use deadpool_sqlite::rusqlite::{
+    Connection, OptionalExtension, Row, Transaction, TransactionBehavior,
+};
+use deadpool_sqlite::{Config, Hook, HookError, Pool, Runtime};
+use rand::distr::{Alphabetic, SampleString};
+
+/// Only used by the baseline migration, databases made before versioning may
+/// have any subset of its columns.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), AppError> {
+    let exist = conn.query_row(
+        &format!(
+            "SELECT EXISTS(SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1)",
+            table
+        ),
+        (column,),
+        |row| row.get::<_, bool>(0),
+    )?;
+    if !exist {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            (),
+        )?;
+    }
+    return Ok(());
+}
+
+/// Schema as of the first versioned release, also brings unversioned
+/// databases of older versions up to it.
+fn migrate_baseline(tx: &Transaction) -> Result<(), AppError> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS files(
+            file_sha256sum TEXT PRIMARY KEY,
+            ref_count INTEGER
+        )",
+        (),
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS urls(
+            tail TEXT PRIMARY KEY,
+            file_sha256sum TEXT,
+            mimetype TEXT,
+            expires_at INTEGER
+        )",
+        (),
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS index_expires_at ON urls(expires_at)",
+        (),
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS collection_files(
+            collection TEXT,
+            tail TEXT,
+            filename TEXT,
+            PRIMARY KEY (collection, tail)
+        )",
+        (),
+    )?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS index_collection_files_tail ON collection_files(tail)",
+        (),
+    )?;
+    // '' for blobs stored as is, otherwise the compression of the blob
+    add_column_if_missing(tx, "files", "encoding", "TEXT NOT NULL DEFAULT ''")?;
+    // size of the uncompressed content, NULL for rows of older versions
+    add_column_if_missing(tx, "files", "size", "INTEGER")?;
+    // size of the blob as stored, NULL for rows of older versions
+    add_column_if_missing(tx, "files", "stored_size", "INTEGER")?;
+    // NULL for urls created by older versions
+    add_column_if_missing(tx, "urls", "created_at", "INTEGER")?;
+    add_column_if_missing(tx, "urls", "last_accessed_at", "INTEGER")?;
+    add_column_if_missing(tx, "urls", "access_count", "INTEGER NOT NULL DEFAULT 0")?;
+    return Ok(());
+}
+
+/// Lets the LRU eviction read urls in order instead of sorting all of them.
+fn migrate_lru_index(tx: &Transaction) -> Result<(), AppError> {
+    tx.execute(
+        "CREATE INDEX index_urls_lru ON urls(COALESCE(last_accessed_at, 0), expires_at)",
+        (),
+    )?;
+    return Ok(());
+}
+
+type Migration = fn(&Transaction) -> Result<(), AppError>;
+
+/// Step `i` brings the schema from `user_version` `i` to `i + 1`. Released
+/// steps are never edited, schema changes go into a new step.
+const MIGRATIONS: &[Migration] = &[migrate_baseline, migrate_lru_index];
+
+fn gen_tail(tx: &Transaction, tail_len: usize) -> Result<String, AppError> {
+    let max_attamps = conf().gen_tail_max_attamps;
+    for _ in 0..max_attamps {
+        let try_tail = Alphabetic.sample_string(&mut rand::rng(), tail_len);
+        let exist = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM urls WHERE tail = ?1)
+                OR EXISTS(SELECT 1 FROM collection_files WHERE collection = ?1)",
+            (&try_tail,),
+            |row| row.get::<_, bool>(0),
+        )?;
+
+        if !exist {
+            return Ok(try_tail);
+        }
+    }
+    return Err(AppError::TailDrained);
+}
+
+fn insert_url(tx: &Transaction, tail: &str, url: &NewUrl) -> Result<(), AppError> {
+    // the blob is rewritten on every upload, so the row follows its encoding
+    tx.execute(
+        "INSERT INTO files(file_sha256sum, ref_count, encoding, size, stored_size)
+        VALUES (?1, 1, ?2, ?3, ?4)
+        ON CONFLICT DO UPDATE SET
+            ref_count = ref_count + 1, encoding = excluded.encoding, size = excluded.size,
+            stored_size = excluded.stored_size",
+        (
+            &url.file_sha256sum,
+            &url.encoding,
+            url.size,
+            url.stored_size,
+        ),
+    )?;
+
+    tx.execute(
+        "INSERT INTO urls(tail, file_sha256sum, mimetype, created_at, expires_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            tail,
+            &url.file_sha256sum,
+            &url.mimetype,
+            url.created_at,
+            url.expires_at,
+        ),
+    )?;
+    return Ok(());
+}
+
+/// Removes a url and decrements the ref count of its blob.
+fn remove_url(tx: &Transaction, tail: &str) -> Result<bool, AppError> {
+    let file_sha256sum = tx
+        .query_row(
+            "DELETE FROM urls WHERE tail = ?1 RETURNING file_sha256sum",
+            (tail,),
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    let Some(file_sha256sum) = file_sha256sum else {
+        return Ok(false);
+    };
+    tx.execute("DELETE FROM collection_files WHERE tail = ?1", (tail,))?;
+    tx.execute(
+        "UPDATE files SET ref_count = ref_count - 1 WHERE file_sha256sum = ?1",
+        (&file_sha256sum,),
+    )?;
+    tx.execute(
+        "DELETE FROM files WHERE file_sha256sum = ?1 AND ref_count <= 0",
+        (&file_sha256sum,),
+    )?;
+    return Ok(true);
+}
+
+/// Evicts urls until the blobs of `urls` fit under `max_total_storage`. Runs
+/// in the transaction adding them, so concurrent uploads can't all fit.
+fn make_room(tx: &Transaction, urls: &[NewUrl]) -> Result<(), AppError> {
+    if conf().max_total_storage == 0 {
+        return Ok(());
+    }
+    let mut names = HashSet::new();
+    let mut incoming = 0;
+    for url in urls {
+        if !names.insert(url.file_sha256sum.clone()) {
+            continue;
+        }
+        let exist = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM files WHERE file_sha256sum = ?1)",
+            (&url.file_sha256sum,),
+            |row| row.get::<_, bool>(0),
+        )?;
+        if !exist {
+            incoming += url.stored_size as u64;
+        }
+    }
+    let total = tx.query_row(TOTAL_STORED_SIZE, (), |row| row.get::<_, i64>(0))?;
+    let Some((needed, order_by)) = space_needed(total as u64, incoming)? else {
+        return Ok(());
+    };
+
+    let mut plan = EvictionPlan::new(needed, names);
+    let mut stmt = tx.prepare(&eviction_query(order_by))?;
+    let mut rows = stmt.query(())?;
+    while let Some(row) = rows.next()? {
+        let size = row.get::<_, i64>(3)? as u64;
+        if plan.pick(row.get(0)?, row.get(1)?, row.get(2)?, size) {
+            break;
+        }
+    }
+    if !plan.is_enough() {
+        return Err(AppError::StorageFull);
+    }
+    for tail in &plan.tails {
+        remove_url(tx, tail)?;
+    }
+    tracing::info!(
+        "evicted {} urls to free {} bytes",
+        plan.tails.len(),
+        plan.freed
+    );
+    return Ok(());
+}
+
+/// Reads the row selected with `URL_FILE_COLUMNS` starting at `offset`.
+fn url_file_from_row(
+    row: &Row,
+    offset: usize,
+) -> Result<UrlFile, deadpool_sqlite::rusqlite::Error> {
+    return Ok(UrlFile {
+        file_sha256sum: row.get(offset)?,
+        mimetype: row.get(offset + 1)?,
+        expires_at: row.get(offset + 2)?,
+        encoding: row.get(offset + 3)?,
+        size: row.get(offset + 4)?,
+        created_at: row.get(offset + 5)?,
+        last_accessed_at: row.get(offset + 6)?,
+        access_count: row.get(offset + 7)?,
+    });
+}
+
+/// The default backend, a single SQLite file. Transactions that write begin
+/// `IMMEDIATE`, so they wait out `busy_timeout` instead of failing when a
+/// read lock can't be upgraded.
+pub struct SqliteRepository {
+    pool: Pool,
+}
+
+impl SqliteRepository {
+    /// Every connection of the pool is set up with the `sqlite_*` settings.
+    pub fn new(path: &Path) -> Result<Self, AppError> {
+        let c = conf();
+        let pragmas = format!(
+            "PRAGMA journal_mode = {};
+            PRAGMA synchronous = {};
+            PRAGMA busy_timeout = {};",
+            if c.sqlite_wal { "WAL" } else { "DELETE" },
+            c.sqlite_synchronous,
+            c.sqlite_busy_timeout
+        );
+        let setup = Hook::async_fn(move |conn, _| {
+            let pragmas = pragmas.clone();
+            Box::pin(async move {
+                conn.interact(move |conn| conn.execute_batch(&pragmas))
+                    .await
+                    .map_err(|e| HookError::message(e.to_string()))?
+                    .map_err(HookError::Backend)?;
+                return Ok(());
+            })
+        });
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let pool = Config::new(path)
+            .builder(Runtime::Tokio1)
+            .map_err(|e| AppError::Database(e.to_string()))?
+            .max_size(c.sqlite_pool_size)
+            .post_create(setup)
+            .build()
+            .map_err(|e| AppError::Database(e.to_string()))?;
+        return Ok(Self { pool });
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    async fn migrate(&self) -> Result<(), AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(|conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let version =
+                    tx.query_row("PRAGMA user_version", (), |row| row.get::<_, usize>(0))?;
+                if version > MIGRATIONS.len() {
+                    return Err(AppError::SchemaTooNew(version, MIGRATIONS.len()));
+                }
+
+                for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+                    tracing::info!("migrating database schema to version {}", i + 1);
+                    migration(&tx)?;
+                }
+                tx.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len()))?;
+                tx.commit()?;
+                return Ok(());
+            })
+            .await?;
+    }
+
+    async fn add_url(&self, tail_len: usize, url: NewUrl) -> Result<String, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                make_room(&tx, std::slice::from_ref(&url))?;
+                let tail = gen_tail(&tx, tail_len)?;
+                insert_url(&tx, &tail, &url)?;
+                tx.commit()?;
+                return Ok(tail);
+            })
+            .await?;
+    }
+
+    async fn add_collection(
+        &self,
+        tail_len: usize,
+        urls: Vec<NewUrl>,
+    ) -> Result<(String, Vec<String>), AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                make_room(&tx, &urls)?;
+                let collection = gen_tail(&tx, tail_len)?;
+                let mut tails = Vec::with_capacity(urls.len());
+                for url in urls {
+                    let tail = gen_tail(&tx, tail_len)?;
+                    insert_url(&tx, &tail, &url)?;
+                    tx.execute(
+                        "INSERT INTO collection_files VALUES (?1, ?2, ?3)",
+                        (&collection, &tail, &url.filename),
+                    )?;
+                    tails.push(tail);
+                }
+                tx.commit()?;
+                return Ok((collection, tails));
+            })
+            .await?;
+    }
+
+    async fn cleanup_expired_urls(&self, now: i64) -> Result<(), AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                tx.execute(
+                    "WITH expired_count AS (
+                        SELECT file_sha256sum, COUNT(*) AS decr
+                        FROM urls
+                        WHERE expires_at <= ?1
+                        GROUP BY file_sha256sum
+                    )
+                    UPDATE files
+                    SET ref_count = ref_count - expired_count.decr
+                    FROM expired_count
+                    WHERE files.file_sha256sum = expired_count.file_sha256sum",
+                    (now,),
+                )?;
+                tx.execute(
+                    "DELETE FROM collection_files
+                    WHERE tail IN (SELECT tail FROM urls WHERE expires_at <= ?1)",
+                    (now,),
+                )?;
+                tx.execute("DELETE FROM urls WHERE expires_at <= ?1", (now,))?;
+                tx.execute("DELETE FROM files WHERE ref_count = 0", ())?;
+                tx.commit()?;
+                return Ok(());
+            })
+            .await?;
+    }
+
+    async fn filter_unreachable_files(&self, names: Vec<String>) -> Result<Vec<String>, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let mut unreachable = Vec::new();
+                for name in names {
+                    let exist = conn.query_row(
+                        "SELECT EXISTS(SELECT 1 FROM files WHERE file_sha256sum = ?1)",
+                        (&name,),
+                        |row| row.get::<_, bool>(0),
+                    )?;
+
+                    if !exist {
+                        unreachable.push(name);
+                    }
+                }
+                return Ok(unreachable);
+            })
+            .await?;
+    }
+
+    async fn total_stored_size(&self) -> Result<u64, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(|conn| {
+                let total = conn.query_row(TOTAL_STORED_SIZE, (), |row| row.get::<_, i64>(0))?;
+                return Ok(total as u64);
+            })
+            .await?;
+    }
+
+    async fn record_access(&self, tail: &str, now: i64) -> Result<(), AppError> {
+        let db_conn = self.pool.get().await?;
+        let tail = tail.to_string();
+        return db_conn
+            .interact(move |conn| {
+                conn.execute(
+                    "UPDATE urls SET last_accessed_at = ?2, access_count = access_count + 1
+                    WHERE tail = ?1",
+                    (&tail, now),
+                )?;
+                return Ok(());
+            })
+            .await?;
+    }
+
+    async fn list_files_without_size(&self) -> Result<Vec<String>, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT file_sha256sum FROM files WHERE size IS NULL OR stored_size IS NULL",
+                )?;
+                let names = stmt
+                    .query_map((), |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()?;
+                return Ok(names);
+            })
+            .await?;
+    }
+
+    async fn set_file_sizes(&self, sizes: Vec<(String, u64)>) -> Result<(), AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                for (name, size) in sizes {
+                    tx.execute(
+                        "UPDATE files SET size = COALESCE(size, ?2), stored_size = ?2
+                        WHERE file_sha256sum = ?1",
+                        (&name, size as i64),
+                    )?;
+                }
+                tx.commit()?;
+                return Ok(());
+            })
+            .await?;
+    }
+
+    async fn list_files(&self) -> Result<Vec<(String, String)>, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(|conn| {
+                let mut stmt = conn.prepare("SELECT file_sha256sum, encoding FROM files")?;
+                let files = stmt
+                    .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<Vec<(String, String)>, _>>()?;
+                return Ok(files);
+            })
+            .await?;
+    }
+
+    async fn get_file_encoding(&self, name: &str) -> Result<Option<String>, AppError> {
+        let db_conn = self.pool.get().await?;
+        let db_param = (name.to_string(),);
+        return db_conn
+            .interact(move |conn| {
+                return conn
+                    .query_row(
+                        "SELECT encoding FROM files WHERE file_sha256sum = ?1",
+                        db_param,
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(AppError::Sqlite);
+            })
+            .await?;
+    }
+
+    async fn list_file_names(&self, after: &str, limit: usize) -> Result<Vec<String>, AppError> {
+        let after = after.to_string();
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT file_sha256sum FROM files WHERE file_sha256sum > ?1
+                    ORDER BY file_sha256sum LIMIT ?2",
+                )?;
+                let names = stmt
+                    .query_map((&after, limit as i64), |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()?;
+                return Ok(names);
+            })
+            .await?;
+    }
+
+    async fn remove_files(&self, names: Vec<String>) -> Result<usize, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let mut removed = 0;
+                for name in names {
+                    tx.execute(
+                        "DELETE FROM collection_files
+                        WHERE tail IN (SELECT tail FROM urls WHERE file_sha256sum = ?1)",
+                        (&name,),
+                    )?;
+                    removed +=
+                        tx.execute("DELETE FROM urls WHERE file_sha256sum = ?1", (&name,))?;
+                    tx.execute("DELETE FROM files WHERE file_sha256sum = ?1", (&name,))?;
+                }
+                tx.commit()?;
+                return Ok(removed);
+            })
+            .await?;
+    }
+
+    async fn check_ref_counts(&self) -> Result<Vec<(String, Option<i64>, i64)>, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(|conn| {
+                let mut stmt = conn.prepare(CHECK_REF_COUNTS)?;
+                return stmt
+                    .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(AppError::Sqlite);
+            })
+            .await?;
+    }
+
+    async fn fix_ref_counts(&self) -> Result<(), AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(|conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                for sql in FIX_REF_COUNTS {
+                    tx.execute(sql, ())?;
+                }
+                tx.commit()?;
+                return Ok(());
+            })
+            .await?;
+    }
+
+    async fn delete_url(&self, tail: &str) -> Result<usize, AppError> {
+        let tail = tail.to_string();
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let mut removed = 0;
+                if remove_url(&tx, &tail)? {
+                    removed += 1;
+                } else {
+                    let tails = tx
+                        .prepare("SELECT tail FROM collection_files WHERE collection = ?1")?
+                        .query_map((&tail,), |row| row.get(0))?
+                        .collect::<Result<Vec<String>, _>>()?;
+                    for tail in tails {
+                        if remove_url(&tx, &tail)? {
+                            removed += 1;
+                        }
+                    }
+                }
+                tx.commit()?;
+                return Ok(removed);
+            })
+            .await?;
+    }
+
+    async fn stats(&self) -> Result<DbStats, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(|conn| {
+                return Ok(conn.query_row(STATS, (), |row| {
+                    Ok(DbStats {
+                        urls: row.get(0)?,
+                        collections: row.get(1)?,
+                        files: row.get(2)?,
+                        size: row.get(3)?,
+                        stored_size: row.get(4)?,
+                    })
+                })?);
+            })
+            .await?;
+    }
+
+    async fn list_urls(&self, after: &str, limit: usize) -> Result<Vec<UrlRecord>, AppError> {
+        let after = after.to_string();
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT urls.tail, urls.file_sha256sum, COALESCE(urls.mimetype, ''),
+                        urls.created_at, urls.expires_at, urls.last_accessed_at,
+                        urls.access_count, COALESCE(files.encoding, ''), files.size,
+                        files.stored_size, collection_files.collection, collection_files.filename
+                    FROM urls
+                    LEFT JOIN files ON files.file_sha256sum = urls.file_sha256sum
+                    LEFT JOIN collection_files ON collection_files.tail = urls.tail
+                    WHERE urls.tail > ?1
+                    ORDER BY urls.tail LIMIT ?2",
+                )?;
+                let records = stmt
+                    .query_map((&after, limit as i64), |row| {
+                        Ok(UrlRecord {
+                            tail: row.get(0)?,
+                            file_sha256sum: row.get(1)?,
+                            mimetype: row.get(2)?,
+                            created_at: row.get(3)?,
+                            expires_at: row.get(4)?,
+                            last_accessed_at: row.get(5)?,
+                            access_count: row.get(6)?,
+                            encoding: row.get(7)?,
+                            size: row.get(8)?,
+                            stored_size: row.get(9)?,
+                            collection: row.get(10)?,
+                            filename: row.get(11)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(records);
+            })
+            .await?;
+    }
+
+    async fn filter_importable(&self, records: Vec<UrlRecord>) -> Result<Vec<UrlRecord>, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let mut importable = Vec::new();
+                for r in records {
+                    let taken = conn.query_row(
+                        "SELECT EXISTS(SELECT 1 FROM urls WHERE tail = ?1)
+                            OR EXISTS(SELECT 1 FROM collection_files WHERE collection = ?1)
+                            OR EXISTS(SELECT 1 FROM urls WHERE tail = ?2)",
+                        (&r.tail, &r.collection),
+                        |row| row.get::<_, bool>(0),
+                    )?;
+                    if !taken {
+                        importable.push(r);
+                    }
+                }
+                return Ok(importable);
+            })
+            .await?;
+    }
+
+    async fn import_urls(&self, records: Vec<UrlRecord>) -> Result<usize, AppError> {
+        let db_conn = self.pool.get().await?;
+        return db_conn
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let mut imported = 0;
+                for r in records {
+                    let taken = tx.query_row(
+                        "SELECT EXISTS(SELECT 1 FROM urls WHERE tail = ?1)
+                            OR EXISTS(SELECT 1 FROM collection_files WHERE collection = ?1)
+                            OR EXISTS(SELECT 1 FROM urls WHERE tail = ?2)",
+                        (&r.tail, &r.collection),
+                        |row| row.get::<_, bool>(0),
+                    )?;
+                    if taken {
+                        continue;
+                    }
+
+                    tx.execute(
+                        "INSERT INTO files(file_sha256sum, ref_count, encoding, size, stored_size)
+                        VALUES (?1, 1, ?2, ?3, ?4)
+                        ON CONFLICT DO UPDATE SET ref_count = ref_count + 1",
+                        (&r.file_sha256sum, &r.encoding, r.size, r.stored_size),
+                    )?;
+                    tx.execute(
+                        "INSERT INTO urls(tail, file_sha256sum, mimetype, created_at, expires_at,
+                            last_accessed_at, access_count)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                        (
+                            &r.tail,
+                            &r.file_sha256sum,
+                            &r.mimetype,
+                            r.created_at,
+                            r.expires_at,
+                            r.last_accessed_at,
+                            r.access_count,
+                        ),
+                    )?;
+                    if let Some(collection) = &r.collection {
+                        tx.execute(
+                            "INSERT INTO collection_files(collection, tail, filename)
+                            VALUES (?1, ?2, ?3)",
+                            (
+                                collection,
+                                &r.tail,
+                                r.filename.as_deref().unwrap_or_default(),
+                            ),
+                        )?;
+                    }
+                    imported += 1;
+                }
+                tx.commit()?;
+                return Ok(imported);
+            })
+            .await?;
+    }
+
+    fn close(&self) {
+        self.pool.close();
+    }
+
+    async fn get_file_by_url(&self, tail: &str) -> Result<Option<UrlFile>, AppError> {
+        let db_conn = self.pool.get().await?;
+        let db_param = (tail.to_string(),);
+        return db_conn
+            .interact(move |conn| {
+                return conn
+                    .query_row(
+                        &format!(
+                            "SELECT {} FROM urls JOIN files USING (file_sha256sum) WHERE tail = ?1",
+                            URL_FILE_COLUMNS
+                        ),
+                        db_param,
+                        |row| url_file_from_row(row, 0),
+                    )
+                    .optional()
+                    .map_err(AppError::Sqlite);
+            })
+            .await?;
+    }
+
+    async fn get_collection_files(
+        &self,
+        collection: &str,
+    ) -> Result<Vec<(String, UrlFile)>, AppError> {
+        let db_conn = self.pool.get().await?;
+        let db_param = (collection.to_string(),);
+        return db_conn
+            .interact(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT collection_files.filename, {}
+                    FROM collection_files
+                    JOIN urls ON urls.tail = collection_files.tail
+                    JOIN files USING (file_sha256sum)
+                    WHERE collection_files.collection = ?1
+                    ORDER BY collection_files.rowid",
+                    URL_FILE_COLUMNS
+                ))?;
+                return stmt
+                    .query_map(db_param, |row| {
+                        Ok((row.get(0)?, url_file_from_row(row, 1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(AppError::Sqlite);
+            })
+            .await?;
+    }
+}
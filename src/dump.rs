@@ -0,0 +1,173 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::compression::{Encoding, verify_data};
+use crate::db::{Repository, UrlRecord};
+use crate::error::AppError;
+use crate::storage::{BLOB_LOCK, storage};
+use crate::utils::is_blob_name;
+
+use axum::body::Bytes;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio_util::io::StreamReader;
+
+/// Urls read from the database or the dump at a time.
+const BATCH_SIZE: usize = 1000;
+const URLS_FILE: &str = "urls.jsonl";
+const BLOBS_DIR: &str = "blobs";
+
+/// Writes every url to `dir/urls.jsonl`, one JSON object per line, and the
+/// blobs they refer to, as stored, to `dir/blobs`. Urls whose blob is missing
+/// from storage are left out. Returns how many urls and blobs were written.
+pub async fn export_dump(db: &dyn Repository, dir: &Path) -> Result<(usize, usize), AppError> {
+    let blobs_dir = dir.join(BLOBS_DIR);
+    tokio::fs::create_dir_all(&blobs_dir).await?;
+    let mut urls_file = BufWriter::new(tokio::fs::File::create(dir.join(URLS_FILE)).await?);
+
+    let (mut urls, mut blobs) = (0, 0);
+    let mut after = String::new();
+    loop {
+        let records = db.list_urls(&after, BATCH_SIZE).await?;
+        let Some(last) = records.last() else {
+            break;
+        };
+        after = last.tail.clone();
+
+        for record in records {
+            let path = blobs_dir.join(&record.file_sha256sum);
+            if !tokio::fs::try_exists(&path).await? {
+                if storage().size(&record.file_sha256sum).await?.is_none() {
+                    tracing::warn!(
+                        "skipping url {}, blob {} is missing",
+                        record.tail,
+                        record.file_sha256sum
+                    );
+                    continue;
+                }
+                let (stream, _) = storage().stream(&record.file_sha256sum).await?;
+                let mut file = tokio::fs::File::create(&path).await?;
+                tokio::io::copy(&mut StreamReader::new(stream), &mut file).await?;
+                blobs += 1;
+            }
+
+            let line = serde_json::to_string(&record).map_err(std::io::Error::from)?;
+            urls_file.write_all(line.as_bytes()).await?;
+            urls_file.write_all(b"\n").await?;
+            urls += 1;
+        }
+    }
+    urls_file.flush().await?;
+    return Ok((urls, blobs));
+}
+
+/// Stores the blobs of a batch and adds its urls. Only blobs of urls that will
+/// be added and without a `files` row are stored, so a blob in use is never
+/// replaced by one of a different encoding, and only if their content hashes
+/// to their name. `BLOB_LOCK` is held like an upload, though it only keeps off
+/// a cleanup in this process, a server running the file cleanup leaves the
+/// blobs alone for `cleanup_grace_period` after they were written.
+async fn import_batch(
+    db: &dyn Repository,
+    blobs_dir: &Path,
+    records: Vec<UrlRecord>,
+) -> Result<usize, AppError> {
+    let _guard = BLOB_LOCK.read().await;
+    let records = records
+        .into_iter()
+        .filter(|record| {
+            // anything but a sha256sum could escape `blobs/`
+            let valid = is_blob_name(&record.file_sha256sum);
+            if !valid {
+                tracing::warn!(
+                    "skipping url {}, '{}' is no blob name",
+                    record.tail,
+                    record.file_sha256sum
+                );
+            }
+            return valid;
+        })
+        .collect();
+    let records = db.filter_importable(records).await?;
+
+    let mut names = records
+        .iter()
+        .map(|record| record.file_sha256sum.clone())
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    names.dedup();
+    let new_names = db
+        .filter_unreachable_files(names)
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut stored_sizes = HashMap::new();
+    let mut missing = HashSet::new();
+    let mut importable = Vec::with_capacity(records.len());
+    for mut record in records {
+        let name = record.file_sha256sum.clone();
+        if new_names.contains(&name) && !stored_sizes.contains_key(&name) {
+            let data = match missing.contains(&name) {
+                true => None,
+                false => match tokio::fs::read(blobs_dir.join(&name)).await {
+                    Ok(data) => Some(data),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(AppError::IO(e)),
+                },
+            };
+            let Some(data) = data else {
+                tracing::warn!("skipping url {}, blob {} is missing", record.tail, name);
+                missing.insert(name);
+                continue;
+            };
+            let data = Bytes::from(data);
+            let Some(encoding) = Encoding::parse(&record.encoding) else {
+                tracing::warn!(
+                    "skipping url {}, blob {} has unknown encoding '{}'",
+                    record.tail,
+                    name,
+                    record.encoding
+                );
+                continue;
+            };
+            if !verify_data(&name, encoding, data.clone()).await? {
+                tracing::warn!(
+                    "skipping url {}, blob {} doesn't match its sha256sum",
+                    record.tail,
+                    name
+                );
+                continue;
+            }
+            let size = data.len() as i64;
+            storage().put(&name, data).await?;
+            stored_sizes.insert(name.clone(), size);
+        }
+        record.stored_size = stored_sizes.get(&name).copied();
+        importable.push(record);
+    }
+    return db.import_urls(importable).await;
+}
+
+/// Reads a dump written by `export_dump`, storing its blobs and adding its urls
+/// under their original tails. Returns how many urls were imported and skipped.
+pub async fn import_dump(db: &dyn Repository, dir: &Path) -> Result<(usize, usize), AppError> {
+    let blobs_dir = dir.join(BLOBS_DIR);
+    let mut lines = BufReader::new(tokio::fs::File::open(dir.join(URLS_FILE)).await?).lines();
+
+    let (mut imported, mut read) = (0, 0);
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        batch.push(serde_json::from_str::<UrlRecord>(&line).map_err(std::io::Error::from)?);
+        read += 1;
+        if batch.len() == BATCH_SIZE {
+            imported += import_batch(db, &blobs_dir, std::mem::take(&mut batch)).await?;
+        }
+    }
+    if !batch.is_empty() {
+        imported += import_batch(db, &blobs_dir, batch).await?;
+    }
+    return Ok((imported, read - imported));
+}
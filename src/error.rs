@@ -1,3 +1,8 @@
+use axum::{
+    body::Body,
+    http::{Response, StatusCode},
+    response::{IntoResponse, Json},
+};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -11,15 +16,39 @@ pub enum AppError {
     #[error("sqlite error: {0}")]
     Sqlite(#[from] deadpool_sqlite::rusqlite::Error),
 
+    #[error("postgres connection pool error: {}", with_sources(.0))]
+    PostgresPool(#[from] deadpool_postgres::PoolError),
+
+    #[error("postgres error: {}", with_sources(.0))]
+    Postgres(#[from] deadpool_postgres::tokio_postgres::Error),
+
+    #[error("database error: {0}")]
+    Database(String),
+
     #[error("io error: {0}")]
     IO(#[from] std::io::Error),
 
     #[error("multipart error: {0}")]
     Multipart(#[from] axum::extract::multipart::MultipartError),
 
+    #[error("multipart rejection: {0}")]
+    MultipartRejection(#[from] axum::extract::multipart::MultipartRejection),
+
+    #[error("body error: {0}")]
+    Body(#[from] axum::Error),
+
     #[error("request error: {0}")]
     RequestError(#[from] nyquest::Error),
 
+    #[error("storage error: {0}")]
+    Storage(String),
+
+    #[error("blob {0} doesn't match its sha256sum")]
+    BlobCorrupted(String),
+
+    #[error("zip error: {0}")]
+    Zip(#[from] async_zip::error::ZipError),
+
     #[error("magic error: {0}")]
     MagicError(String),
 
@@ -38,12 +67,170 @@ pub enum AppError {
     #[error("file too large")]
     FileTooLarge,
 
+    #[error("more than {0} files in one upload")]
+    TooManyFiles(usize),
+
+    #[error("upload too large")]
+    BatchTooLarge,
+
+    #[error("storage full")]
+    StorageFull,
+
     #[error("tail drained")]
     TailDrained,
 
     #[error("tail not found")]
     TailNotFound,
 
-    #[error("config parse error: {0}")]
-    ConfigParseError(String),
+    #[error("config parse error: {}", .0.join("; "))]
+    ConfigParseError(Vec<String>),
+
+    #[error("database schema version {0} is newer than the latest known {1}, upgrade webpaste")]
+    SchemaTooNew(usize, usize),
+}
+
+/// Appends the causes, tokio-postgres leaves out why a connection or a TLS
+/// handshake failed otherwise.
+fn with_sources(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message = format!("{}: {}", message, cause_message);
+        }
+        source = cause.source();
+    }
+    return message;
+}
+
+impl AppError {
+    /// A stable machine readable code for JSON error responses.
+    pub fn code(&self) -> &'static str {
+        return match self {
+            AppError::Pool(_) => "pool_error",
+            AppError::Interaction(_) => "interaction_error",
+            AppError::Sqlite(_) => "sqlite_error",
+            AppError::PostgresPool(_) => "pool_error",
+            AppError::Postgres(_) => "postgres_error",
+            AppError::Database(_) => "database_error",
+            AppError::IO(_) => "io_error",
+            AppError::Multipart(_) => "multipart_error",
+            AppError::MultipartRejection(_) => "multipart_rejected",
+            AppError::Body(_) => "body_error",
+            AppError::RequestError(e) => match e {
+                nyquest::Error::InvalidUrl => "url_invalid",
+                nyquest::Error::ResponseTooLarge => "response_too_large",
+                nyquest::Error::RequestTimeout => "request_timeout",
+                nyquest::Error::NonSuccessfulStatusCode(_) => "request_failed",
+                _ => "request_error",
+            },
+            AppError::Storage(_) => "storage_error",
+            AppError::BlobCorrupted(_) => "blob_corrupted",
+            AppError::Zip(_) => "zip_error",
+            AppError::MagicError(_) => "magic_error",
+            AppError::NoFileUploaded => "no_file_uploaded",
+            AppError::FieldHasNoName => "field_has_no_name",
+            AppError::LenParseError(_) => "len_parse_error",
+            AppError::ExpiresParseError(_) => "expires_parse_error",
+            AppError::FileTooLarge => "file_too_large",
+            AppError::TooManyFiles(_) => "too_many_files",
+            AppError::BatchTooLarge => "batch_too_large",
+            AppError::StorageFull => "storage_full",
+            AppError::TailDrained => "tail_drained",
+            AppError::TailNotFound => "tail_not_found",
+            AppError::ConfigParseError(_) => "config_parse_error",
+            AppError::SchemaTooNew(..) => "schema_too_new",
+        };
+    }
+
+    /// The status code and the message shown to clients, internal errors are
+    /// logged here and get no message.
+    fn status(&self) -> (StatusCode, Option<String>) {
+        return match self {
+            AppError::Multipart(e) => (e.status(), None),
+            AppError::MultipartRejection(e) => (e.status(), None),
+            AppError::Body(_) => (StatusCode::BAD_REQUEST, None),
+            AppError::RequestError(e) => match e {
+                nyquest::Error::InvalidUrl => {
+                    (StatusCode::BAD_REQUEST, Some("url is invalid".to_string()))
+                }
+                nyquest::Error::ResponseTooLarge => (
+                    StatusCode::BAD_REQUEST,
+                    Some("response too large".to_string()),
+                ),
+                nyquest::Error::RequestTimeout => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Some("request timeout".to_string()),
+                ),
+                nyquest::Error::NonSuccessfulStatusCode(c) => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Some(format!("request failed with status code {}", c.code())),
+                ),
+                other_error => {
+                    tracing::error!("{}", other_error);
+                    (StatusCode::INTERNAL_SERVER_ERROR, None)
+                }
+            },
+            AppError::NoFileUploaded => (
+                StatusCode::BAD_REQUEST,
+                Some("no 'file' or 'url' specified".to_string()),
+            ),
+            AppError::FieldHasNoName => (StatusCode::BAD_REQUEST, None),
+            AppError::LenParseError(msg) => (
+                StatusCode::BAD_REQUEST,
+                Some(format!("parse error in 'len' field: {}", msg)),
+            ),
+            AppError::ExpiresParseError(msg) => (
+                StatusCode::BAD_REQUEST,
+                Some(format!("parse error in 'expires' field: {}", msg)),
+            ),
+            AppError::FileTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, None),
+            AppError::TooManyFiles(max) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Some(format!("at most {} files may be uploaded at once", max)),
+            ),
+            AppError::BatchTooLarge => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Some("files of an upload are too large together".to_string()),
+            ),
+            AppError::StorageFull => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                Some("storage is full, try again later".to_string()),
+            ),
+            AppError::TailDrained => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Some(
+                    "cannot generate an unique url, try specifying a larger 'tail_len'".to_string(),
+                ),
+            ),
+            AppError::TailNotFound => (StatusCode::NOT_FOUND, None),
+            other_error => {
+                tracing::error!("{}", other_error);
+                (StatusCode::INTERNAL_SERVER_ERROR, None)
+            }
+        };
+    }
+
+    /// Renders the error as plain text, or as `{"error": {"code", "message"}}`
+    /// when `json` is set.
+    pub fn into_response(self, json: bool) -> Response<Body> {
+        let (status, message) = self.status();
+        if !json {
+            return match message {
+                Some(message) => (status, format!("{}\n", message)).into_response(),
+                None => status.into_response(),
+            };
+        }
+
+        let message =
+            message.unwrap_or(status.canonical_reason().unwrap_or_default().to_lowercase());
+        let body = serde_json::json!({
+            "error": {
+                "code": self.code(),
+                "message": message,
+            }
+        });
+        return (status, Json(body)).into_response();
+    }
 }
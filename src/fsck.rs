@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+
+use crate::db::Repository;
+use crate::error::AppError;
+use crate::storage::storage;
+
+/// Problems found by `fsck`.
+pub struct FsckReport {
+    /// name, recorded `ref_count` (`None` without a `files` row) and the
+    /// number of urls actually referring to the blob
+    pub ref_count_mismatches: Vec<(String, Option<i64>, i64)>,
+    /// blobs referred to by urls but missing from storage
+    pub missing_blobs: Vec<String>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        return self.ref_count_mismatches.is_empty() && self.missing_blobs.is_empty();
+    }
+}
+
+/// Recomputes ref counts from `urls` and looks for blobs missing from
+/// storage, every problem found is logged. With `repair` set ref counts are
+/// corrected and urls of missing blobs removed.
+pub async fn fsck(db: &dyn Repository, repair: bool) -> Result<FsckReport, AppError> {
+    let ref_count_mismatches = db.check_ref_counts().await?;
+    for (name, recorded, actual) in &ref_count_mismatches {
+        match recorded {
+            Some(recorded) => tracing::warn!(
+                "blob {} has ref_count {} but {} urls refer to it",
+                name,
+                recorded,
+                actual
+            ),
+            None => tracing::warn!(
+                "blob {} has no files row but {} urls refer to it",
+                name,
+                actual
+            ),
+        }
+    }
+    if repair && !ref_count_mismatches.is_empty() {
+        db.fix_ref_counts().await?;
+        tracing::info!("fixed {} ref counts", ref_count_mismatches.len());
+    }
+
+    let missing_blobs = check_missing_blobs(
+        db,
+        ref_count_mismatches
+            .iter()
+            .filter(|(_, recorded, actual)| recorded.is_none() && *actual > 0)
+            .map(|(name, _, _)| name.clone()),
+        repair,
+    )
+    .await?;
+
+    return Ok(FsckReport {
+        ref_count_mismatches,
+        missing_blobs,
+    });
+}
+
+/// Looks for blobs in `files`, or in `also_referenced`, that are missing from
+/// storage, every one found is logged. With `repair` set their urls are
+/// removed, unless storage lists no blobs at all.
+pub async fn check_missing_blobs(
+    db: &dyn Repository,
+    also_referenced: impl IntoIterator<Item = String>,
+    repair: bool,
+) -> Result<Vec<String>, AppError> {
+    // the database is listed first, a blob uploaded in between is then
+    // either not referenced yet or already stored
+    let mut referenced = db
+        .list_files()
+        .await?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<HashSet<_>>();
+    referenced.extend(also_referenced);
+    let stored = storage()
+        .list()
+        .await?
+        .into_iter()
+        .map(|blob| blob.name)
+        .collect::<HashSet<_>>();
+    let mut missing_blobs = Vec::new();
+    for name in referenced {
+        if !stored.contains(&name) && storage().size(&name).await?.is_none() {
+            tracing::warn!("blob {} is missing from storage", name);
+            missing_blobs.push(name);
+        }
+    }
+    if repair && !missing_blobs.is_empty() {
+        // more likely a wrong upload_file_dir or bucket than lost blobs
+        if stored.is_empty() {
+            return Err(AppError::Storage(
+                "storage lists no blobs at all, not removing any urls".to_string(),
+            ));
+        }
+        let removed = db.remove_files(missing_blobs.clone()).await?;
+        tracing::info!("removed {} urls of missing blobs", removed);
+    }
+    return Ok(missing_blobs);
+}
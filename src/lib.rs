@@ -1,13 +1,27 @@
+#![allow(clippy::needless_return)]
+
 mod access;
+mod archive;
 mod cleanup;
+mod compression;
 mod config;
 mod db;
+mod dump;
 mod error;
+mod fsck;
+mod quota;
+mod storage;
 mod upload;
 mod utils;
 
-pub use access::handle_access;
-pub use cleanup::init_cleanup;
+pub use access::{handle_access, handle_access_head, handle_info};
+pub use cleanup::{CleanupStats, backfill_file_sizes, gc, init_cleanup};
+pub use compression::response_compression_layer;
 pub use config::*;
-pub use db::init_db;
-pub use upload::handle_upload;
+pub use db::{Db, DbStats, PostgresRepository, Repository, SqliteRepository, init_db};
+pub use dump::{export_dump, import_dump};
+pub use error::AppError;
+pub use fsck::{FsckReport, check_missing_blobs, fsck};
+pub use storage::{Storage, init_storage, migrate_fs_layout, storage};
+pub use upload::{handle_put, handle_upload};
+pub use utils::format_size;
@@ -1,39 +1,272 @@
+#![allow(clippy::needless_return)]
+
+use std::future::IntoFuture;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::time::Duration;
 
+use axum::extract::DefaultBodyLimit;
 use axum::routing::{get, post};
 use axum::{Router, response::Html};
-use deadpool_sqlite::{Config, Runtime};
+use clap::{Parser, Subcommand};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_util::sync::CancellationToken;
+
+use webpaste::{
+    AppError, Db, backfill_file_sizes, export_dump, gc, handle_access, handle_access_head,
+    handle_info, handle_put, handle_upload, import_dump, init_cleanup, init_db, init_storage,
+    migrate_fs_layout, response_compression_layer,
+};
+use webpaste::{
+    check_missing_blobs, conf, format_size, fsck, init_config, parse_override, reload_config,
+};
 
-use webpaste::{conf, init_config};
-use webpaste::{handle_access, handle_upload, init_cleanup, init_db};
+/// Resolves on SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = sigterm.recv() => (),
+    }
+}
 
 async fn handle_root() -> Html<&'static str> {
     return Html(include_str!("../index.html"));
 }
 
+/// A pastebin for files.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Config file, the compiled-in defaults are used without one
+    #[arg(short, long, global = true, env = "WEBPASTE_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Same as --config, so `webpaste <config>` keeps working
+    #[arg(hide = true)]
+    config_path: Option<PathBuf>,
+
+    /// Overrides a config value, may be given several times. Takes precedence
+    /// over WEBPASTE_* environment variables, which take precedence over the
+    /// config file
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override, global = true)]
+    overrides: Vec<(String, toml::Value)>,
+
+    /// Overrides `listen_addr`
+    #[arg(long, global = true)]
+    listen_addr: Option<String>,
+
+    /// Overrides `base_url`
+    #[arg(long, global = true)]
+    base_url: Option<String>,
+
+    /// Overrides `upload_file_dir`
+    #[arg(long, global = true)]
+    upload_file_dir: Option<String>,
+
+    /// Overrides `database_file`
+    #[arg(long, global = true)]
+    database_file: Option<String>,
+
+    /// Overrides `database_url`
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve uploads and downloads, the default
+    Serve,
+    /// Run the url and the file cleanup once
+    Gc,
+    /// Check ref counts and look for blobs missing from storage
+    Fsck {
+        /// Fix ref counts and remove urls of missing blobs
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Print the number of urls and blobs and their sizes
+    Stats,
+    /// Delete a url, or every url of a collection
+    Delete { tail: String },
+    /// Add the urls and blobs of a directory written by `export`
+    Import { dir: PathBuf },
+    /// Write every url and blob into a directory
+    Export { dir: PathBuf },
+    /// Move blobs of the old flat upload directory layout into shards
+    MigrateLayout,
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
     nyquest_preset::register();
 
-    init_config(std::env::args().nth(1).map(|s| PathBuf::from(s))).unwrap();
+    if let Err(e) = run(Cli::parse()).await {
+        match e {
+            AppError::ConfigParseError(errors) => {
+                eprintln!("invalid config:");
+                for error in errors {
+                    eprintln!("  {}", error);
+                }
+            }
+            e => eprintln!("error: {}", e),
+        }
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), AppError> {
+    let mut overrides = cli.overrides;
+    let flags = [
+        ("listen_addr", cli.listen_addr),
+        ("base_url", cli.base_url),
+        ("upload_file_dir", cli.upload_file_dir),
+        ("database_file", cli.database_file),
+        ("database_url", cli.database_url),
+    ];
+    for (key, value) in flags {
+        if let Some(value) = value {
+            overrides.push((key.to_string(), toml::Value::String(value)));
+        }
+    }
+    init_config(cli.config.or(cli.config_path), &overrides)?;
 
-    let db_cfg = Config::new(&conf().database_file);
-    let db_pool = Arc::new(db_cfg.create_pool(Runtime::Tokio1).unwrap());
-    init_db(&db_pool).await.unwrap();
+    let command = cli.command.unwrap_or(Command::Serve);
+    if let Command::MigrateLayout = command {
+        let moved = migrate_fs_layout().await?;
+        tracing::info!("moved {} blobs into shards", moved);
+        return Ok(());
+    }
 
-    init_cleanup(&db_pool);
+    init_storage().await?;
+    let db = init_db().await?;
+
+    match command {
+        Command::Serve => serve(db.clone()).await?,
+        Command::Gc => {
+            let stats = gc(db.as_ref()).await?;
+            println!(
+                "deleted {} unreachable blobs of {} scanned, kept {} written within the grace period",
+                stats.deleted, stats.scanned, stats.recent
+            );
+            if stats.failed > 0 {
+                println!("failed to delete {} blobs", stats.failed);
+                std::process::exit(1);
+            }
+        }
+        Command::Fsck { repair } => {
+            let report = fsck(db.as_ref(), repair).await?;
+            if report.is_clean() {
+                tracing::info!("no problems found");
+            } else if !repair {
+                std::process::exit(1);
+            }
+        }
+        Command::Stats => {
+            let stats = db.stats().await?;
+            println!("urls:        {}", stats.urls);
+            println!("collections: {}", stats.collections);
+            println!("blobs:       {}", stats.files);
+            println!("size:        {}", format_size(stats.size as u64));
+            println!("stored size: {}", format_size(stats.stored_size as u64));
+            if conf().max_total_storage > 0 {
+                println!("storage limit: {}", format_size(conf().max_total_storage));
+            }
+        }
+        Command::Delete { tail } => match db.delete_url(&tail).await? {
+            0 => {
+                println!("no url or collection {}", tail);
+                std::process::exit(1);
+            }
+            removed => println!("deleted {} urls", removed),
+        },
+        Command::Import { dir } => {
+            let (imported, skipped) = import_dump(db.as_ref(), &dir).await?;
+            println!("imported {} urls, skipped {}", imported, skipped);
+        }
+        Command::Export { dir } => {
+            let (urls, blobs) = export_dump(db.as_ref(), &dir).await?;
+            println!("exported {} urls and {} blobs", urls, blobs);
+        }
+        Command::MigrateLayout => unreachable!(),
+    }
+    db.close();
+    return Ok(());
+}
+
+async fn serve(db: Db) -> Result<(), AppError> {
+    backfill_file_sizes(db.as_ref()).await?;
+    // urls of blobs lost while stopped would fail until removed
+    if let Err(e) = check_missing_blobs(db.as_ref(), Vec::new(), true).await {
+        tracing::error!("cannot remove urls of missing blobs: {}", e);
+    }
+
+    let shutdown = CancellationToken::new();
+    let cleanup_tasks = init_cleanup(&db, &shutdown);
 
     let app = Router::new()
         .route("/", get(handle_root))
-        .route("/", post(handle_upload))
-        .route("/{path}", get(handle_access))
-        .with_state(db_pool);
+        .route("/", post(handle_upload).put(handle_upload))
+        .route(
+            "/{path}",
+            get(handle_access).head(handle_access_head).put(handle_put),
+        )
+        .route("/{path}/info", get(handle_info))
+        .layer(response_compression_layer())
+        // uploads enforce the limits of the current config themselves
+        .layer(DefaultBodyLimit::disable())
+        .with_state(db.clone());
 
     let listen_addr = &conf().listen_addr;
-    let listener = tokio::net::TcpListener::bind(listen_addr).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(listen_addr).await?;
 
     tracing::info!("listening on {}", listen_addr);
-    axum::serve(listener, app).await.unwrap();
+    let server =
+        axum::serve(listener, app).with_graceful_shutdown(shutdown.clone().cancelled_owned());
+    let mut server = tokio::spawn(server.into_future());
+    let mut sighup = signal(SignalKind::hangup())?;
+    let shutdown_signal = shutdown_signal();
+    tokio::pin!(shutdown_signal);
+    loop {
+        tokio::select! {
+            result = &mut server => return Ok(result.unwrap()?),
+            _ = &mut shutdown_signal => break,
+            _ = sighup.recv() => {
+                if let Err(e) = reload_config() {
+                    tracing::error!("keeping the running config: {}", e);
+                }
+            }
+        }
+    }
+
+    // stop accepting connections and the background tasks, then give
+    // in-flight requests until the deadline to finish
+    tracing::info!("shutting down");
+    shutdown.cancel();
+    let timeout = Duration::from_secs(conf().shutdown_timeout);
+    let deadline = tokio::time::Instant::now() + timeout;
+    if tokio::time::timeout_at(deadline, &mut server)
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "in-flight requests didn't finish within {:?}, aborting them",
+            timeout
+        );
+        server.abort();
+    }
+    for mut task in cleanup_tasks {
+        if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+            tracing::warn!(
+                "a background task didn't stop within {:?}, aborting it",
+                timeout
+            );
+            task.abort();
+        }
+    }
+    tracing::info!("shut down");
+    return Ok(());
 }
@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::conf;
+use crate::config::EvictionPolicy;
+use crate::db::Repository;
+use crate::error::AppError;
+
+/// Rejects `blobs`, given as name and stored size, early if they can't fit
+/// under `max_total_storage` even by evicting, so they aren't written for
+/// nothing. Blobs already stored don't count. The transaction adding their
+/// urls checks again and evicts, see `space_needed`.
+pub async fn check_space(db: &dyn Repository, blobs: &[(String, u64)]) -> Result<(), AppError> {
+    let c = conf();
+    if c.max_total_storage == 0 {
+        return Ok(());
+    }
+
+    let names = blobs.iter().map(|(name, _)| name.clone()).collect();
+    let new_names = db.filter_unreachable_files(names).await?;
+    let incoming = blobs
+        .iter()
+        .filter(|(name, _)| new_names.contains(name))
+        .map(|(_, size)| size)
+        .sum::<u64>();
+    if incoming > c.max_total_storage {
+        return Err(AppError::StorageFull);
+    }
+    if c.eviction_policy == EvictionPolicy::None
+        && db.total_stored_size().await? + incoming > c.max_total_storage
+    {
+        return Err(AppError::StorageFull);
+    }
+    return Ok(());
+}
+
+/// Returns how many bytes have to be freed before `incoming` new bytes fit
+/// next to `total` stored ones and the `ORDER BY` to evict urls in, or `None`
+/// if they fit already.
+pub fn space_needed(total: u64, incoming: u64) -> Result<Option<(u64, &'static str)>, AppError> {
+    let c = conf();
+    if c.max_total_storage == 0 {
+        return Ok(None);
+    }
+    if incoming > c.max_total_storage {
+        return Err(AppError::StorageFull);
+    }
+    let needed = (total + incoming).saturating_sub(c.max_total_storage);
+    if needed == 0 {
+        return Ok(None);
+    }
+
+    let order_by = match c.eviction_policy {
+        EvictionPolicy::None => return Err(AppError::StorageFull),
+        EvictionPolicy::Expiry => "expires_at",
+        // matches index_urls_lru
+        EvictionPolicy::Lru => "COALESCE(last_accessed_at, 0), expires_at",
+    };
+    return Ok(Some((needed, order_by)));
+}
+
+/// Selects every url in eviction order with the ref count and stored size of
+/// its blob, valid on both backends.
+pub fn eviction_query(order_by: &str) -> String {
+    return format!(
+        "SELECT urls.tail, urls.file_sha256sum, COALESCE(files.ref_count, 1),
+            COALESCE(files.stored_size, files.size, 0)
+        FROM urls JOIN files ON files.file_sha256sum = urls.file_sha256sum
+        ORDER BY {}",
+        order_by
+    );
+}
+
+/// Picks urls to evict, fed in eviction order, until the blobs they leave
+/// unreferenced add up to the bytes needed.
+pub struct EvictionPlan {
+    needed: u64,
+    pub freed: u64,
+    /// blobs of the upload, evicting their urls would free nothing
+    keep: HashSet<String>,
+    /// references left to each blob once the urls picked so far are gone
+    refs: HashMap<String, i64>,
+    pub tails: Vec<String>,
+}
+
+impl EvictionPlan {
+    pub fn new(needed: u64, keep: HashSet<String>) -> Self {
+        return Self {
+            needed,
+            freed: 0,
+            keep,
+            refs: HashMap::new(),
+            tails: Vec::new(),
+        };
+    }
+
+    /// Takes the next url in eviction order, returns true once enough is freed.
+    pub fn pick(
+        &mut self,
+        tail: String,
+        file_sha256sum: String,
+        ref_count: i64,
+        size: u64,
+    ) -> bool {
+        if self.keep.contains(&file_sha256sum) {
+            return false;
+        }
+        let refs = self.refs.entry(file_sha256sum).or_insert(ref_count);
+        *refs -= 1;
+        if *refs == 0 {
+            self.freed += size;
+        }
+        self.tails.push(tail);
+        return self.is_enough();
+    }
+
+    pub fn is_enough(&self) -> bool {
+        return self.freed >= self.needed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pick_all(plan: &mut EvictionPlan, urls: &[(&str, &str, i64, u64)]) -> bool {
+        return urls.iter().any(|(tail, sha, refs, size)| {
+            plan.pick(tail.to_string(), sha.to_string(), *refs, *size)
+        });
+    }
+
+    #[test]
+    fn frees_a_blob_once_all_its_urls_are_picked() {
+        let mut plan = EvictionPlan::new(100, HashSet::new());
+        assert!(!plan.pick("a".to_string(), "x".to_string(), 2, 100));
+        assert_eq!(plan.freed, 0);
+        assert!(plan.pick("b".to_string(), "x".to_string(), 2, 100));
+        assert_eq!(plan.freed, 100);
+        assert_eq!(plan.tails, ["a", "b"]);
+    }
+
+    #[test]
+    fn stops_as_soon_as_enough_is_freed() {
+        let mut plan = EvictionPlan::new(150, HashSet::new());
+        let enough = pick_all(
+            &mut plan,
+            &[("a", "x", 1, 100), ("b", "y", 1, 100), ("c", "z", 1, 100)],
+        );
+        assert!(enough);
+        assert!(plan.is_enough());
+        assert_eq!(plan.freed, 200);
+        assert_eq!(plan.tails, ["a", "b"]);
+    }
+
+    #[test]
+    fn never_picks_urls_of_kept_blobs() {
+        let mut plan = EvictionPlan::new(100, HashSet::from(["x".to_string()]));
+        let enough = pick_all(&mut plan, &[("a", "x", 1, 100), ("b", "y", 2, 100)]);
+        assert!(!enough);
+        assert_eq!(plan.freed, 0);
+        assert_eq!(plan.tails, ["b"]);
+    }
+
+    #[test]
+    fn nothing_needed_is_enough_right_away() {
+        assert!(EvictionPlan::new(0, HashSet::new()).is_enough());
+    }
+}
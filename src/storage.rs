@@ -0,0 +1,99 @@
+mod fs;
+mod s3;
+
+use std::pin::Pin;
+use std::sync::OnceLock;
+
+use crate::conf;
+use crate::config::StorageBackend;
+use crate::error::AppError;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::Stream;
+use tokio::sync::RwLock;
+
+pub use fs::FsStorage;
+pub use s3::S3Storage;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// A blob as found when listing the storage.
+pub struct StoredBlob {
+    pub name: String,
+    /// unix timestamp of the last write
+    pub modified_at: i64,
+}
+
+/// Held shared by uploads from writing their blobs until their urls are
+/// committed and exclusively by the file cleanup while it deletes, so a blob
+/// is never deleted between being written and being referenced.
+pub static BLOB_LOCK: RwLock<()> = RwLock::const_new(());
+
+/// Where blobs live, every blob is addressed by its name (the sha256sum of
+/// its content).
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, name: &str, data: Bytes) -> Result<(), AppError>;
+
+    async fn get(&self, name: &str) -> Result<Bytes, AppError>;
+
+    /// Returns the content as a stream together with its size.
+    async fn stream(&self, name: &str) -> Result<(ByteStream, u64), AppError>;
+
+    /// Returns the size of a blob, or `None` if it doesn't exist.
+    async fn size(&self, name: &str) -> Result<Option<u64>, AppError>;
+
+    /// Returns the unix timestamp of the last write of a blob, or `None` if
+    /// it doesn't exist.
+    async fn modified_at(&self, name: &str) -> Result<Option<i64>, AppError>;
+
+    async fn delete(&self, name: &str) -> Result<(), AppError>;
+
+    /// Returns all stored blobs, blobs still being written are not listed.
+    async fn list(&self) -> Result<Vec<StoredBlob>, AppError>;
+
+    /// Moves a blob aside for inspection, it's no longer served nor listed.
+    async fn quarantine(&self, name: &str) -> Result<(), AppError>;
+
+    /// Deletes temporary files last written at or before `cutoff`, left by
+    /// crashes or aborted writes. Returns how many were deleted.
+    async fn remove_stale_temp(&self, _cutoff: i64) -> Result<usize, AppError> {
+        return Ok(0);
+    }
+}
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+pub async fn init_storage() -> Result<(), AppError> {
+    let storage: Box<dyn Storage> = match conf().storage_backend {
+        StorageBackend::Fs => {
+            // blobs of the flat layout are neither found nor listed, so they
+            // have to be in their shards before anything touches storage
+            let fs = FsStorage::new(conf().upload_file_dir.clone());
+            let moved = fs.migrate_flat_layout().await?;
+            if moved > 0 {
+                tracing::info!(
+                    "moved {} blobs of the old flat layout of {} into shards",
+                    moved,
+                    conf().upload_file_dir.display()
+                );
+            }
+            Box::new(fs)
+        }
+        StorageBackend::S3 => Box::new(S3Storage::new().await?),
+    };
+    STORAGE.get_or_init(|| storage);
+    return Ok(());
+}
+
+pub fn storage() -> &'static dyn Storage {
+    return STORAGE.get().unwrap().as_ref();
+}
+
+/// Moves the blobs of `upload_file_dir` from the flat layout into shards.
+pub async fn migrate_fs_layout() -> Result<usize, AppError> {
+    return FsStorage::new(conf().upload_file_dir.clone())
+        .migrate_flat_layout()
+        .await;
+}
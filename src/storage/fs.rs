@@ -0,0 +1,203 @@
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::error::AppError;
+use crate::storage::{ByteStream, Storage, StoredBlob};
+use crate::utils::is_blob_name;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use rand::distr::{Alphanumeric, SampleString};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+/// Blobs are written here first and renamed into place once complete.
+const TMP_DIR: &str = ".tmp";
+/// Corrupted blobs found by the scrub end up here.
+const QUARANTINE_DIR: &str = ".quarantine";
+
+/// Last modification of a file in seconds since the epoch, `None` if it's gone.
+async fn modified_at(path: &Path) -> Result<Option<i64>, AppError> {
+    let modified = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata.modified()?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(AppError::IO(e)),
+    };
+    return Ok(Some(
+        modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64),
+    ));
+}
+
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: PathBuf) -> Self {
+        return Self { root };
+    }
+
+    /// Blobs are sharded by the first two bytes of their name, i.e.
+    /// `ab/cd/abcd...`, to keep directories small.
+    fn path(&self, name: &str) -> PathBuf {
+        if name.len() < 4 || !name.is_char_boundary(2) || !name.is_char_boundary(4) {
+            return self.root.join(name);
+        }
+        return self.root.join(&name[..2]).join(&name[2..4]).join(name);
+    }
+
+    async fn list_dir(dir: &Path, dirs: bool) -> Result<Vec<(String, PathBuf)>, AppError> {
+        let mut entries = Vec::new();
+        let mut read_dir = match tokio::fs::read_dir(dir).await {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+            Err(e) => return Err(AppError::IO(e)),
+        };
+        while let Some(entry) = read_dir.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if (dirs && file_type.is_dir()) || (!dirs && file_type.is_file()) {
+                entries.push((
+                    entry.file_name().to_string_lossy().to_string(),
+                    entry.path(),
+                ));
+            }
+        }
+        return Ok(entries);
+    }
+
+    /// Names of blobs lying directly in the root, left from the flat layout.
+    pub async fn list_flat(&self) -> Result<Vec<String>, AppError> {
+        let files = Self::list_dir(&self.root, false).await?;
+        return Ok(files
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| is_blob_name(name))
+            .collect());
+    }
+
+    /// Moves blobs of the flat layout into their shard, returns how many were moved.
+    pub async fn migrate_flat_layout(&self) -> Result<usize, AppError> {
+        let mut moved = 0;
+        for name in self.list_flat().await? {
+            let path = self.path(&name);
+            if path == self.root.join(&name) {
+                continue;
+            }
+            tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+            tokio::fs::rename(self.root.join(&name), path).await?;
+            moved += 1;
+        }
+        return Ok(moved);
+    }
+}
+
+#[async_trait]
+impl Storage for FsStorage {
+    /// Writes to a temporary file and renames it over the final path, so
+    /// readers never see a partial blob and concurrent writers of the same
+    /// content don't interleave.
+    async fn put(&self, name: &str, data: Bytes) -> Result<(), AppError> {
+        let path = self.path(name);
+        let dir = path.parent().unwrap();
+        let tmp_dir = self.root.join(TMP_DIR);
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::create_dir_all(&tmp_dir).await?;
+
+        let tmp_path = tmp_dir.join(format!(
+            "{}.{}",
+            name,
+            Alphanumeric.sample_string(&mut rand::rng(), 8)
+        ));
+        let result = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp_path, &path).await?;
+            tokio::fs::File::open(dir).await?.sync_all().await?;
+            return Ok::<(), std::io::Error>(());
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+        return Ok(result?);
+    }
+
+    async fn get(&self, name: &str) -> Result<Bytes, AppError> {
+        return Ok(Bytes::from(tokio::fs::read(self.path(name)).await?));
+    }
+
+    async fn stream(&self, name: &str) -> Result<(ByteStream, u64), AppError> {
+        let file = tokio::fs::File::open(self.path(name)).await?;
+        let size = file.metadata().await?.len();
+        return Ok((Box::pin(ReaderStream::new(file)), size));
+    }
+
+    async fn size(&self, name: &str) -> Result<Option<u64>, AppError> {
+        return match tokio::fs::metadata(self.path(name)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(AppError::IO(e)),
+        };
+    }
+
+    async fn modified_at(&self, name: &str) -> Result<Option<i64>, AppError> {
+        return modified_at(&self.path(name)).await;
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), AppError> {
+        return match tokio::fs::remove_file(self.path(name)).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(AppError::IO(e)),
+        };
+    }
+
+    async fn list(&self) -> Result<Vec<StoredBlob>, AppError> {
+        let mut blobs = Vec::new();
+        for (shard_name, shard) in Self::list_dir(&self.root, true).await? {
+            if shard_name == TMP_DIR || shard_name == QUARANTINE_DIR {
+                continue;
+            }
+            for (_, subshard) in Self::list_dir(&shard, true).await? {
+                for (name, path) in Self::list_dir(&subshard, false).await? {
+                    // anything else wasn't written by webpaste and couldn't
+                    // be deleted by name anyway
+                    if !is_blob_name(&name) || self.path(&name) != path {
+                        continue;
+                    }
+                    // deleted since the directory was read
+                    let Some(modified_at) = modified_at(&path).await? else {
+                        continue;
+                    };
+                    blobs.push(StoredBlob { name, modified_at });
+                }
+            }
+        }
+        return Ok(blobs);
+    }
+
+    async fn quarantine(&self, name: &str) -> Result<(), AppError> {
+        let dir = self.root.join(QUARANTINE_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::rename(self.path(name), dir.join(name)).await?;
+        return Ok(());
+    }
+
+    async fn remove_stale_temp(&self, cutoff: i64) -> Result<usize, AppError> {
+        let mut removed = 0;
+        for (_, path) in Self::list_dir(&self.root.join(TMP_DIR), false).await? {
+            if modified_at(&path).await?.is_some_and(|m| m <= cutoff) {
+                match tokio::fs::remove_file(&path).await {
+                    Ok(_) => removed += 1,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(AppError::IO(e)),
+                }
+            }
+        }
+        return Ok(removed);
+    }
+}
@@ -1,23 +1,35 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
 
-use crate::db::add_url;
+use crate::compression::compress_for_storage;
+use crate::db::{Db, NewUrl, Repository};
 use crate::error::AppError;
-use crate::conf;
-use crate::utils::get_full_path;
+use crate::quota::check_space;
+use crate::storage::{BLOB_LOCK, storage};
+use crate::utils::{sanitize_filename, wants_json};
+use crate::{MAX_TAIL_LEN, conf};
 
 use axum::{
     body::{Body, Bytes},
-    extract::{Multipart, State},
+    extract::{FromRequest, Multipart, Path, Query, Request, State},
     http,
-    response::IntoResponse,
+    response::{IntoResponse, Json},
 };
+use bytes::BytesMut;
 use chrono::Utc;
-use deadpool_sqlite::Pool;
+use futures_util::StreamExt;
+use http_body_util::Limited;
 use humantime::parse_duration;
+use nyquest::ClientBuilder;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 
-fn calc_retention(size: usize) -> i64 {
+/// Seconds an upload is kept by default, from the first retention rule it
+/// matches or else the curve between the expire bounds.
+fn calc_retention(mimetype: &str, size: usize) -> i64 {
     let c = &conf();
+    if let Some(rule) = c.retention_rules.iter().find(|r| r.matches(mimetype, size)) {
+        return rule.retention;
+    }
     return (c.min_expire_duration as f64
         + (c.min_expire_duration - c.max_expire_duration) as f64
             * (size as f64 / c.max_file_size as f64 - 1.).powf(3.)) as i64;
@@ -30,161 +42,402 @@ pub fn guess_mime(data: &[u8]) -> Result<String, AppError> {
         .load(&Default::default())
         .map_err(|e| AppError::MagicError(e.to_string()))?;
     let mut mimetype = cookie
-        .buffer(&data)
+        .buffer(data)
         .map_err(|e| AppError::MagicError(e.to_string()))?;
     if mimetype == "text/plain" {
         let mut encdet = chardetng::EncodingDetector::new();
-        encdet.feed(&data, true);
+        encdet.feed(data, true);
         mimetype += &format!("; charset={}", encdet.guess(None, true).name());
     }
     return Ok(mimetype);
 }
 
-async fn parse_multipart(mut multipart: Multipart) -> Result<(Bytes, usize, i64), AppError> {
-    let mut data = None;
-    let mut tail_len = conf().default_tail_len;
+struct UploadFile {
+    data: Bytes,
+    filename: Option<String>,
+}
+
+fn calc_expires_at(
+    expires: &Option<String>,
+    now: i64,
+    mimetype: &str,
+    size: usize,
+) -> Result<i64, AppError> {
+    return match expires {
+        Some(expires) => match expires.chars().all(|c| c.is_numeric()) {
+            true => expires
+                .parse::<i64>()
+                .map_err(|e| AppError::ExpiresParseError(e.to_string())),
+            false => Ok(now
+                + parse_duration(expires)
+                    .map_err(|e| AppError::ExpiresParseError(e.to_string()))?
+                    .as_secs() as i64),
+        },
+        None => Ok(now + calc_retention(mimetype, size)),
+    };
+}
+
+struct UploadOptions {
+    tail_len: usize,
+    expires: Option<String>,
+}
+
+fn parse_tail_len(s: &str) -> Result<usize, AppError> {
+    let len = s
+        .parse::<usize>()
+        .map_err(|e| AppError::LenParseError(e.to_string()))?;
+    if len == 0 || len > MAX_TAIL_LEN {
+        return Err(AppError::LenParseError(format!(
+            "tail length must be between 1 and {}",
+            MAX_TAIL_LEN
+        )));
+    }
+    return Ok(len);
+}
+
+/// Room for boundaries, headers and form fields besides the files of a
+/// multipart upload.
+const MULTIPART_OVERHEAD: usize = 1 << 20;
+
+async fn parse_multipart(
+    mut multipart: Multipart,
+) -> Result<(Vec<UploadFile>, UploadOptions), AppError> {
+    let c = conf();
+    let mut files = Vec::new();
+    let mut total_size = 0;
+    let mut tail_len = c.default_tail_len;
     let mut expires = None;
 
-    while let Some(field) = multipart.next_field().await? {
-        let name = field.name().ok_or(AppError::FieldHasNoName)?;
-        match name {
-            "file" => match data {
-                None => data = Some(field.bytes().await?),
-                Some(_) => continue,
-            },
-            "url" => match data {
-                None => {
-                    data = Some(Bytes::from_owner(
-                        nyquest::r#async::get(field.text().await?)
-                            .await?
-                            .bytes()
-                            .await?,
-                    ))
+    while let Some(mut field) = multipart.next_field().await? {
+        let name = field.name().ok_or(AppError::FieldHasNoName)?.to_string();
+        if matches!(name.as_str(), "file" | "url") && files.len() >= c.max_batch_files {
+            return Err(AppError::TooManyFiles(c.max_batch_files));
+        }
+        let file = match name.as_str() {
+            "file" => {
+                let filename = field.file_name().map(|s| s.to_string());
+                let mut data = BytesMut::new();
+                while let Some(chunk) = field.chunk().await? {
+                    if data.len() + chunk.len() > c.max_file_size {
+                        return Err(AppError::FileTooLarge);
+                    }
+                    if total_size + data.len() + chunk.len() > c.max_batch_size {
+                        return Err(AppError::BatchTooLarge);
+                    }
+                    data.extend_from_slice(&chunk);
                 }
-                Some(_) => continue,
-            },
+                UploadFile {
+                    data: data.freeze(),
+                    filename,
+                }
+            }
+            "url" => {
+                let url = field.text().await?;
+                let filename = url.split(['?', '#']).next().map(|s| s.to_string());
+                // don't download more than the batch has room for
+                let limit = c.max_file_size.min(c.max_batch_size - total_size);
+                let client = ClientBuilder::default()
+                    .max_response_buffer_size(limit as u64)
+                    .build_async()
+                    .await?;
+                let response = client.request(nyquest::r#async::Request::get(url)).await?;
+                UploadFile {
+                    data: Bytes::from_owner(response.bytes().await?),
+                    filename,
+                }
+            }
             "len" => {
-                tail_len = field
-                    .text()
-                    .await?
-                    .parse::<usize>()
-                    .map_err(|e| AppError::LenParseError(e.to_string()))?
+                tail_len = parse_tail_len(&field.text().await?)?;
+                continue;
+            }
+            "expires" => {
+                if expires.is_none() {
+                    expires = Some(field.text().await?);
+                }
+                continue;
             }
-            "expires" => match expires {
-                None => expires = Some(field.text().await?),
-                Some(_) => continue,
-            },
-            _ => (),
+            _ => continue,
+        };
+
+        if file.data.len() > c.max_file_size {
+            return Err(AppError::FileTooLarge);
+        }
+        total_size += file.data.len();
+        if total_size > c.max_batch_size {
+            return Err(AppError::BatchTooLarge);
         }
+        files.push(file);
     }
 
-    let data = data.ok_or(AppError::NoFileUploaded)?;
+    if files.is_empty() {
+        return Err(AppError::NoFileUploaded);
+    }
 
-    let now = Utc::now().timestamp();
-    let expires_at = match expires {
-        Some(expires) => match expires.chars().all(|c| c.is_numeric()) {
-            true => expires
-                .parse::<i64>()
-                .map_err(|e| AppError::ExpiresParseError(e.to_string()))?,
-            false => {
-                now + parse_duration(&expires)
-                    .map_err(|e| AppError::ExpiresParseError(e.to_string()))?
-                    .as_secs() as i64
-            }
-        },
-        None => now + calc_retention(data.len()),
+    return Ok((files, UploadOptions { tail_len, expires }));
+}
+
+/// Reads upload options of a raw body upload, headers take precedence over
+/// query parameters.
+fn parse_raw_options(
+    headers: &http::HeaderMap,
+    query: &HashMap<String, String>,
+) -> Result<UploadOptions, AppError> {
+    let header_or_query = |header: &str, param: &str| -> Option<String> {
+        return match headers.get(header) {
+            Some(v) => Some(v.to_str().unwrap_or_default().to_string()),
+            None => query.get(param).cloned(),
+        };
     };
 
-    return Ok((data, tail_len, expires_at));
+    let tail_len = match header_or_query("X-Tail-Len", "len") {
+        Some(len) => parse_tail_len(&len)?,
+        None => conf().default_tail_len,
+    };
+    let expires = header_or_query("X-Expires", "expires");
+
+    return Ok(UploadOptions { tail_len, expires });
+}
+
+async fn read_raw_body(body: Body) -> Result<Bytes, AppError> {
+    let max_file_size = conf().max_file_size;
+    let mut data = BytesMut::new();
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > max_file_size {
+            return Err(AppError::FileTooLarge);
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    if data.is_empty() {
+        return Err(AppError::NoFileUploaded);
+    }
+
+    return Ok(data.freeze());
+}
+
+#[derive(Serialize)]
+struct UploadedFile {
+    url: String,
+    tail: String,
+    sha256: String,
+    size: usize,
+    mimetype: String,
+    expires_at: i64,
+    /// seconds from the upload until `expires_at`
+    retention: i64,
+}
+
+#[derive(Serialize)]
+struct UploadedCollection {
+    tail: String,
+    zip_url: String,
+    tar_gz_url: String,
+}
+
+/// Always `{files, collection}`, `collection` is null for single uploads.
+#[derive(Serialize)]
+struct UploadResult {
+    files: Vec<UploadedFile>,
+    collection: Option<UploadedCollection>,
+}
+
+impl UploadResult {
+    fn new(entries: Vec<NewUrl>, sizes: Vec<usize>, tails: Vec<String>) -> Self {
+        let base_url = &conf().base_url;
+        let files = entries
+            .into_iter()
+            .zip(sizes)
+            .zip(tails)
+            .map(|((entry, size), tail)| UploadedFile {
+                url: format!("{}/{}", base_url, tail),
+                tail,
+                sha256: entry.file_sha256sum,
+                size,
+                mimetype: entry.mimetype,
+                expires_at: entry.expires_at,
+                retention: entry.expires_at - entry.created_at,
+            })
+            .collect();
+        return Self {
+            files,
+            collection: None,
+        };
+    }
+
+    fn with_collection(mut self, tail: String) -> Self {
+        let base_url = &conf().base_url;
+        self.collection = Some(UploadedCollection {
+            zip_url: format!("{}/{}.zip", base_url, tail),
+            tar_gz_url: format!("{}/{}.tar.gz", base_url, tail),
+            tail,
+        });
+        return self;
+    }
+
+    fn into_text(self) -> String {
+        let mut body = String::new();
+        for file in self.files {
+            body += &format!("{}\n", file.url);
+        }
+        if let Some(collection) = self.collection {
+            body += &format!("{}\n{}\n", collection.zip_url, collection.tar_gz_url);
+        }
+        return body;
+    }
 }
 
-async fn upload(db_pool: &Pool, multipart: Multipart) -> Result<String, AppError> {
-    let (data, tail_len, expires_at) = parse_multipart(multipart).await?;
-    if data.len() > conf().max_file_size {
+/// Stores the uploaded files, for batch uploads a collection grouping them is
+/// created as well.
+async fn upload(
+    db: &dyn Repository,
+    files: Vec<UploadFile>,
+    options: UploadOptions,
+) -> Result<UploadResult, AppError> {
+    let UploadOptions { tail_len, expires } = options;
+    if files.iter().any(|f| f.data.len() > conf().max_file_size) {
         return Err(AppError::FileTooLarge);
     }
 
-    let file_sha256sum = hex::encode(Sha256::digest(&data));
-    let mimetype = guess_mime(&data)?;
+    let mut entries = Vec::with_capacity(files.len());
+    let mut blobs = Vec::with_capacity(files.len());
+    for file in &files {
+        let mimetype = guess_mime(&file.data)?;
+        let (blob, encoding) = compress_for_storage(&file.data, &mimetype).await?;
+        let created_at = Utc::now().timestamp();
+        entries.push(NewUrl {
+            file_sha256sum: hex::encode(Sha256::digest(&file.data)),
+            expires_at: calc_expires_at(&expires, created_at, &mimetype, file.data.len())?,
+            mimetype,
+            created_at,
+            filename: String::new(),
+            encoding: encoding.as_str().to_string(),
+            size: file.data.len() as i64,
+            stored_size: blob.len() as i64,
+        });
+        blobs.push(blob);
+    }
+    let sizes = files.iter().map(|f| f.data.len()).collect::<Vec<_>>();
 
-    let tail = add_url(&db_pool, tail_len, &file_sha256sum, &mimetype, expires_at).await?;
+    let stored = entries
+        .iter()
+        .map(|entry| (entry.file_sha256sum.clone(), entry.stored_size as u64))
+        .collect::<Vec<_>>();
+    check_space(db, &stored).await?;
 
-    match tokio::fs::write(get_full_path(&file_sha256sum), &data).await {
-        Ok(_) => Ok(()),
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::AlreadyExists => Ok(()),
-            _ => Err(e),
-        },
-    }?;
+    // blobs go first, so a committed url always points at a complete blob,
+    // a blob left behind by a failed insert is collected by the cleanup. The
+    // lock keeps the cleanup from deleting them until the urls are committed.
+    let _guard = BLOB_LOCK.read().await;
+    for (entry, blob) in entries.iter().zip(blobs) {
+        storage().put(&entry.file_sha256sum, blob).await?;
+    }
+
+    if let [entry] = entries.as_slice() {
+        let tail = db.add_url(tail_len, entry.clone()).await?;
+        return Ok(UploadResult::new(entries, sizes, vec![tail]));
+    }
+
+    let mut used_names = HashSet::new();
+    for (i, (entry, file)) in entries.iter_mut().zip(&files).enumerate() {
+        let name = file.filename.as_deref().map(sanitize_filename);
+        entry.filename = match name {
+            Some(name) if !name.is_empty() && used_names.insert(name.clone()) => name,
+            _ => format!("{}-{}", i + 1, entry.file_sha256sum),
+        };
+    }
+
+    let (collection, tails) = db.add_collection(tail_len, entries.clone()).await?;
 
-    return Ok(tail);
+    return Ok(UploadResult::new(entries, sizes, tails).with_collection(collection));
 }
 
+async fn upload_request(
+    db: &dyn Repository,
+    query: HashMap<String, String>,
+    request: Request,
+) -> Result<UploadResult, AppError> {
+    let is_multipart = request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("multipart/form-data"));
+
+    if is_multipart {
+        // files are checked as they arrive, this bounds everything else
+        let limit = conf().max_batch_size + MULTIPART_OVERHEAD;
+        let request = request.map(|body| Body::new(Limited::new(body, limit)));
+        let multipart = Multipart::from_request(request, &()).await?;
+        let (files, options) = parse_multipart(multipart).await?;
+        return upload(db, files, options).await;
+    }
+
+    let options = parse_raw_options(request.headers(), &query)?;
+    let data = read_raw_body(request.into_body()).await?;
+    let file = UploadFile {
+        data,
+        filename: None,
+    };
+    return upload(db, vec![file], options).await;
+}
+
+async fn upload_put(
+    db: &dyn Repository,
+    filename: String,
+    query: HashMap<String, String>,
+    headers: &http::HeaderMap,
+    body: Body,
+) -> Result<UploadResult, AppError> {
+    let options = parse_raw_options(headers, &query)?;
+    let data = read_raw_body(body).await?;
+    let file = UploadFile {
+        data,
+        filename: Some(filename),
+    };
+    return upload(db, vec![file], options).await;
+}
+
+/// Accepts either a multipart form or, for any other content type, the raw
+/// request body as a single file.
 pub async fn handle_upload(
-    State(db_pool): State<Arc<Pool>>,
-    multipart: Multipart,
+    State(db): State<Db>,
+    Query(query): Query<HashMap<String, String>>,
+    request: Request,
 ) -> http::Response<Body> {
-    match upload(&db_pool, multipart).await {
-        Ok(tail) => return format!("{}/{}\n", conf().base_url, tail).into_response(),
-        Err(e) => match e {
-            AppError::Multipart(e) => return e.status().into_response(),
-            AppError::RequestError(e) => match e {
-                nyquest::Error::InvalidUrl => {
-                    return (http::StatusCode::BAD_REQUEST, "url is invalid\n").into_response();
-                }
-                nyquest::Error::ResponseTooLarge => {
-                    return (http::StatusCode::BAD_REQUEST, "response too large\n").into_response();
-                }
-                nyquest::Error::RequestTimeout => {
-                    return (http::StatusCode::SERVICE_UNAVAILABLE, "request timeout\n")
-                        .into_response();
-                }
-                nyquest::Error::NonSuccessfulStatusCode(c) => {
-                    return (
-                        http::StatusCode::SERVICE_UNAVAILABLE,
-                        format!("request failed with status code {}\n", c.code()),
-                    )
-                        .into_response();
-                }
-                other_error => {
-                    tracing::error!("{}", other_error);
-                    return http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
-                }
-            },
-            AppError::NoFileUploaded => {
-                return (
-                    http::StatusCode::BAD_REQUEST,
-                    "no 'file' or 'url' specified\n",
-                )
-                    .into_response();
-            }
-            AppError::FieldHasNoName => return http::StatusCode::BAD_REQUEST.into_response(),
-            AppError::LenParseError(msg) => {
-                return (
-                    http::StatusCode::BAD_REQUEST,
-                    format!("parse error in 'len' field: {}\n", msg),
-                )
-                    .into_response();
-            }
-            AppError::ExpiresParseError(msg) => {
-                return (
-                    http::StatusCode::BAD_REQUEST,
-                    format!("parse error in 'expires' field: {}\n", msg),
-                )
-                    .into_response();
-            }
-            AppError::FileTooLarge => return http::StatusCode::PAYLOAD_TOO_LARGE.into_response(),
-            AppError::TailDrained => {
-                return (
-                    http::StatusCode::SERVICE_UNAVAILABLE,
-                    "cannot generate an unique url, try specifying a larger 'tail_len'\n",
-                )
-                    .into_response();
-            }
-            other_error => {
-                tracing::error!("{}", other_error);
-                return http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
-            }
-        },
+    let json = wants_json(request.headers());
+    return upload_response(upload_request(db.as_ref(), query, request).await, json);
+}
+
+/// Takes the request body as the file, so `curl -T file` works.
+pub async fn handle_put(
+    State(db): State<Db>,
+    Path(filename): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: http::HeaderMap,
+    body: Body,
+) -> http::Response<Body> {
+    let json = wants_json(&headers);
+    let result = upload_put(db.as_ref(), filename, query, &headers, body).await;
+    return upload_response(result, json);
+}
+
+/// Successful uploads carry the effective expiry in `X-Expires`, as a unix
+/// timestamp like the request header takes, the earliest one for batches.
+fn upload_response(result: Result<UploadResult, AppError>, json: bool) -> http::Response<Body> {
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => return e.into_response(json),
+    };
+    let expires_at = result.files.iter().map(|file| file.expires_at).min();
+    let mut response = match json {
+        true => Json(result).into_response(),
+        false => result.into_text().into_response(),
+    };
+    if let Some(expires_at) = expires_at {
+        response
+            .headers_mut()
+            .insert("X-Expires", expires_at.into());
     }
+    return response;
 }
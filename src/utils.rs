@@ -1,7 +1,104 @@
-use std::path::PathBuf;
+use axum::http::{HeaderMap, header};
 
-use crate::conf;
+/// Strips any directory components from a client supplied filename.
+pub fn sanitize_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    return match name {
+        "." | ".." => String::new(),
+        _ => name.to_string(),
+    };
+}
+
+/// Whether the client asked for a JSON response through the `Accept` header.
+pub fn wants_json(headers: &HeaderMap) -> bool {
+    return headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("application/json"));
+}
+
+/// Blobs are named by the hex sha256sum of their content.
+pub fn is_blob_name(name: &str) -> bool {
+    return name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit());
+}
+
+const SIZE_UNITS: [(&str, u64); 9] = [
+    ("b", 1),
+    ("kb", 1000),
+    ("kib", 1 << 10),
+    ("mb", 1000 * 1000),
+    ("mib", 1 << 20),
+    ("gb", 1000 * 1000 * 1000),
+    ("gib", 1 << 30),
+    ("tb", 1000 * 1000 * 1000 * 1000),
+    ("tib", 1 << 40),
+];
+
+/// Parses sizes like `512MiB`, `1.5 GB` or `1024`, units are case-insensitive
+/// and `K`, `M`, `G` and `T` alone are decimal like `KB`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    let mut unit = unit.trim().to_lowercase();
+    if unit.len() == 1 && unit != "b" {
+        unit.push('b');
+    }
+    let multiplier = match unit.as_str() {
+        "" => 1,
+        unit => SIZE_UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, multiplier)| *multiplier)
+            .ok_or(format!(
+                "unknown unit in size '{}', expected B, KB, KiB, MB, MiB, GB, GiB, TB or TiB",
+                s
+            ))?,
+    };
+    return Ok((number * multiplier as f64) as u64);
+}
+
+/// Formats a byte count with the largest binary unit it reaches, e.g. `1.5 MiB`.
+pub fn format_size(size: u64) -> String {
+    let Some((unit, multiplier)) = SIZE_UNITS
+        .iter()
+        .filter(|(name, _)| name.ends_with("ib"))
+        .rev()
+        .find(|(_, multiplier)| size >= *multiplier)
+    else {
+        return format!("{} B", size);
+    };
+    let unit = format!("{}iB", unit[..1].to_uppercase());
+    return format!("{:.1} {}", size as f64 / *multiplier as f64, unit);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_reads_numbers_and_units() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size(" 512MiB "), Ok(512 << 20));
+        assert_eq!(parse_size("1.5 GB"), Ok(1_500_000_000));
+        assert_eq!(parse_size("2kib"), Ok(2048));
+        assert_eq!(parse_size("10K"), Ok(10_000));
+        assert_eq!(parse_size("3b"), Ok(3));
+        assert_eq!(parse_size("1TiB"), Ok(1 << 40));
+    }
 
-pub fn get_full_path(filename: &str) -> PathBuf {
-    return conf().upload_file_dir.join(filename);
+    #[test]
+    fn parse_size_rejects_garbage() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MiB").is_err());
+        assert!(parse_size("1.2.3").is_err());
+        assert!(parse_size("5 parsecs").is_err());
+        assert!(parse_size("-1").is_err());
+    }
 }